@@ -57,4 +57,154 @@ cmd="store --db-file integration/test_ok/mypackage_build.ini --tmp-dir target/in
 echo "$cmd" | tee | sh
 
 cmd="store --db-file integration/test_ok/mypackage_build.ini --tmp-dir target/installer  --packages-dir target/packages dependencies"
-echo "$cmd" | tee | sh
\ No newline at end of file
+echo "$cmd" | tee | sh
+cmd="store --db-file integration/test_ok/package_url_build.ini --tmp-dir target/installer  --packages-dir target/packages verify package-url-build"
+echo "$cmd" | tee | sh
+
+# the build shell does not modify an installed package
+cmd="store --db-file integration/test_ok/package_url_build.ini --tmp-dir target/installer  --packages-dir target/packages build-shell package-url-build"
+echo "$cmd" | tee
+echo 'exit' | sh -c "$cmd" >/dev/null
+cmd="store --db-file integration/test_ok/package_url_build.ini --tmp-dir target/installer  --packages-dir target/packages verify package-url-build"
+echo "$cmd" | tee
+if ! echo "$cmd" | sh; then
+  echo "Expected package-url-build to be unchanged by the build shell"
+  exit 1
+fi
+
+echo "patched" >>target/packages/package-url-build@0.1.0/OUTPUT
+cmd="store --db-file integration/test_ok/package_url_build.ini --tmp-dir target/installer  --packages-dir target/packages verify package-url-build"
+echo "$cmd" | tee
+set +e
+echo "$cmd" | sh
+exit_code=$?
+set -e
+if [[ "$exit_code" != "11" ]]; then
+  echo "Expected exit code 11 for modified package"
+  exit 1
+fi
+
+# build scripts see the final path of the package
+if [[ "$(cat target/packages/package-env@0.1.0/PREFIX)" != "$PWD/target/packages/package-env@0.1.0" ]]; then
+  echo "Expected PACKAGE_PREFIX to be the final package directory"
+  exit 1
+fi
+
+# packages installed before receipts were introduced
+rm target/packages/.receipts/package-env@0.1.0.json
+cmd="store --db-file integration/test_ok/package_env.ini --tmp-dir target/installer --packages-dir target/packages install package-env"
+echo "$cmd" | tee
+output=$(echo "$cmd" | sh)
+if ! grep -q "Package package-env@0.1.0 already installed" <<< "$output"; then
+  echo "Expected package-env without receipt to be installed"
+  exit 1
+fi
+cmd="store --db-file integration/test_ok/package_env.ini --tmp-dir target/installer --packages-dir target/packages verify package-env"
+echo "$cmd" | tee
+output=$(echo "$cmd" | sh)
+if ! grep -q "Package package-env@0.1.0 has no receipt" <<< "$output"; then
+  echo "Expected package-env without receipt to be listed by verify"
+  exit 1
+fi
+
+cmd="store --db-file integration/test_errors/invalid_build.ini --tmp-dir target/installer --packages-dir target/packages log invalid-build"
+echo "$cmd" | tee
+output=$(echo "$cmd" | sh)
+if ! grep -q "xxx: command not found" <<< "$output"; then
+  echo "Expected build log of invalid-build"
+  exit 1
+fi
+
+cmd="store --db-file integration/test_errors/invalid_build.ini --tmp-dir target/installer --packages-dir target/packages --keep-failed install invalid-build"
+echo "$cmd" | tee
+set +e
+echo "$cmd" | sh
+exit_code=$?
+set -e
+if [[ "$exit_code" != "8" || ! -d "target/packages/.staging/invalid-build@0.1.0" || -d "target/packages/invalid-build@0.1.0" ]]; then
+  echo "Expected failed build directories to be kept"
+  exit 1
+fi
+
+cmd="store --db-file integration/test_errors/invalid_build.ini --tmp-dir target/installer --packages-dir target/packages build-shell invalid-build"
+echo "$cmd" | tee
+output=$(echo 'echo "PACKAGE_DIR=$PACKAGE_DIR"' | sh -c "$cmd" 2>&1)
+if ! grep -q "PACKAGE_DIR=.*invalid-build@0.1.0" <<< "$output"; then
+  echo "Expected build environment in build shell"
+  exit 1
+fi
+
+rm -rf target/packages_jobs
+mkdir -p target/packages_jobs
+cmd="store --db-file integration/test_ok/transitive_dependencies.ini --tmp-dir target/installer --packages-dir target/packages_jobs --jobs 2 install chain-a"
+echo "$cmd" | tee
+output=$(echo "$cmd" | sh)
+if ! grep -q "^\[chain-a@1.0.0\] Package chain-a@1.0.0 successfully installed" <<< "$output"; then
+  echo "Expected chain-a to be installed in parallel mode"
+  exit 1
+fi
+
+# diamond-b and diamond-c wait for each other, they must run in parallel
+cat >target/diamond.ini <<'EOF'
+[diamond-a@1.0.0]
+depends = diamond-b, diamond-c
+build = cat $DEP_DIAMOND_B_DIR/B $DEP_DIAMOND_C_DIR/C >$PACKAGE_DIR/A
+
+[diamond-b@1.0.0]
+depends = diamond-d
+build = touch $PACKAGES_DIR/diamond-b.started
+        for i in $(seq 50); do test -e $PACKAGES_DIR/diamond-c.started && break; sleep 0.1; done
+        test -e $PACKAGES_DIR/diamond-c.started
+        echo "building b"
+        echo B >$PACKAGE_DIR/B
+
+[diamond-c@1.0.0]
+depends = diamond-d
+build = touch $PACKAGES_DIR/diamond-c.started
+        for i in $(seq 50); do test -e $PACKAGES_DIR/diamond-b.started && break; sleep 0.1; done
+        test -e $PACKAGES_DIR/diamond-b.started
+        echo C >$PACKAGE_DIR/C
+
+[diamond-d@1.0.0]
+build = echo D >$PACKAGE_DIR/D
+EOF
+cmd="store --db-file target/diamond.ini --tmp-dir target/installer --packages-dir target/packages_jobs --jobs 2 --verbose install diamond-a"
+echo "$cmd" | tee
+output=$(echo "$cmd" | sh 2>/dev/null)
+if ! grep -q "^\[diamond-a@1.0.0\] Package diamond-a@1.0.0 successfully installed" <<< "$output"; then
+  echo "Expected diamond-b and diamond-c to be installed in parallel"
+  exit 1
+fi
+if ! grep -q "^\[diamond-b@1.0.0\] building b" <<< "$output"; then
+  echo "Expected build output prefixed with the package id"
+  exit 1
+fi
+
+cmd="store --db-file integration/test_ok/package_url.ini --tmp-dir target/installer --packages-dir target/packages cache list"
+echo "$cmd" | tee
+output=$(echo "$cmd" | sh)
+if ! grep -q "yyy-0.1.0.tar.bz2 .* http://localhost:8000/yyy-0.1.0.tar.bz2" <<< "$output"; then
+  echo "Expected yyy-0.1.0.tar.bz2 in the download cache"
+  exit 1
+fi
+
+cmd="store --db-file integration/test_ok/package_url.ini --tmp-dir target/installer --packages-dir target/packages cache clean"
+echo "$cmd" | tee | sh
+cmd="store --db-file integration/test_ok/package_url.ini --tmp-dir target/installer --packages-dir target/packages cache list"
+output=$(echo "$cmd" | sh)
+if ! grep -q "^0 cached files" <<< "$output"; then
+  echo "Expected an empty download cache"
+  exit 1
+fi
+
+rm -rf target/packages_offline target/installer_offline
+mkdir -p target/packages_offline
+cmd="store --db-file integration/test_ok/package_url.ini --tmp-dir target/installer --packages-dir target/packages fetch yyy"
+echo "$cmd" | tee | sh
+cmd="store --db-file integration/test_ok/package_url.ini --tmp-dir target/installer_offline --packages-dir target/packages_offline --cache-dir target/packages/.cache --offline install yyy"
+echo "$cmd" | tee
+output=$(echo "$cmd" | sh)
+if ! grep -q "yyy-0.1.0.tar.bz2 found in cache" <<< "$output"; then
+  echo "Expected yyy to be installed from the download cache"
+  exit 1
+fi
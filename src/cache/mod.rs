@@ -0,0 +1,365 @@
+use crate::{Checksum, Lock, LockMode};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+// default directory of the download cache, inside the store
+pub const CACHE_DIR: &str = ".cache";
+
+// file of a cache entry containing the url of the download
+const URL_FILE: &str = ".url";
+
+// Downloads shared by all packages and installer directories
+// an entry is a directory named after its key, containing the downloaded file
+// installs use that file directly, it is not copied
+// the key is the checksum of the file when it is known, the url otherwise
+// the least recently used entries are removed when the cache exceeds max_size
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Cache {
+    pub dir: PathBuf,
+    pub max_size: Option<u64>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CacheEntry {
+    pub key: String,
+    pub file: PathBuf,
+    pub url: Option<String>,
+    pub size: u64,
+    pub used: SystemTime,
+}
+
+impl Cache {
+    // sha256-<digest> or url-<digest of the url>
+    pub fn key(url: &str, checksum: Option<&Checksum>) -> String {
+        match checksum {
+            // the same digest may be written in upper or lower case
+            Some(checksum) => format!(
+                "{}-{}",
+                checksum.algorithm.name(),
+                checksum.value.to_lowercase()
+            ),
+            None => {
+                let digest = Sha256::digest(url.as_bytes());
+                let hex = digest
+                    .iter()
+                    .map(|b| format!("{:02x}", b))
+                    .collect::<String>();
+                format!("url-{}", &hex[..32])
+            }
+        }
+    }
+
+    pub fn file(&self, key: &str, basename: &str) -> PathBuf {
+        self.dir.join(key).join(basename)
+    }
+
+    // exclusive while the entry is downloaded or removed
+    // shared while it is used by an install
+    pub fn lock(
+        &self,
+        key: &str,
+        mode: LockMode,
+        wait: bool,
+        prefix: &str,
+    ) -> Result<Lock, String> {
+        Lock::acquire(&self.dir.join(format!("{}.lock", key)), mode, wait, prefix)
+    }
+
+    // the directory of the entry is created, the file is written by the caller
+    pub fn create_entry(&self, key: &str, url: &str) -> Result<(), String> {
+        let dir = self.dir.join(key);
+        fs::create_dir_all(&dir)
+            .map_err(|e| format!("Directory {} can not be created: {}", dir.display(), e))?;
+        fs::write(dir.join(URL_FILE), url)
+            .map_err(|e| format!("Can not write {}: {}", dir.join(URL_FILE).display(), e))
+    }
+
+    // mark the file as recently used
+    pub fn touch(&self, file: &Path) {
+        if let Ok(f) = File::options().write(true).open(file) {
+            let _ = f.set_modified(SystemTime::now());
+        }
+    }
+
+    // complete entries, sorted by key
+    pub fn entries(&self) -> Vec<CacheEntry> {
+        let mut entries = vec![];
+        let dir_entries = match fs::read_dir(&self.dir) {
+            Ok(dir_entries) => dir_entries,
+            Err(_) => return entries,
+        };
+        for dir_entry in dir_entries.flatten() {
+            let path = dir_entry.path();
+            if !path.is_dir() {
+                continue;
+            }
+            let key = dir_entry.file_name().to_string_lossy().to_string();
+            let url = fs::read_to_string(path.join(URL_FILE)).ok();
+            let files = match fs::read_dir(&path) {
+                Ok(files) => files,
+                Err(_) => continue,
+            };
+            for file in files.flatten() {
+                let name = file.file_name().to_string_lossy().to_string();
+                // partial downloads are not entries yet
+                if name == URL_FILE || name.ends_with(".part") || name.ends_with(".part.info") {
+                    continue;
+                }
+                if let Ok(metadata) = file.metadata() {
+                    entries.push(CacheEntry {
+                        key: key.clone(),
+                        file: file.path(),
+                        url: url.clone(),
+                        size: metadata.len(),
+                        used: metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+                    });
+                }
+            }
+        }
+        entries.sort_by(|a, b| a.key.cmp(&b.key));
+        entries
+    }
+
+    pub fn remove(&self, key: &str) -> Result<(), String> {
+        let dir = self.dir.join(key);
+        if dir.exists() {
+            fs::remove_dir_all(&dir)
+                .map_err(|e| format!("Directory {} can not be deleted: {}", dir.display(), e))?;
+        }
+        Ok(())
+    }
+
+    // remove all the entries (including partial downloads) and their lock files
+    // the store is locked, no install is using them
+    // return the number of removed entries
+    pub fn clean(&self) -> Result<usize, String> {
+        let mut count = 0;
+        let dir_entries = match fs::read_dir(&self.dir) {
+            Ok(dir_entries) => dir_entries,
+            Err(_) => return Ok(0),
+        };
+        for dir_entry in dir_entries.flatten() {
+            let path = dir_entry.path();
+            if path.is_dir() {
+                self.remove(&dir_entry.file_name().to_string_lossy())?;
+                count += 1;
+            } else if path.extension().is_some_and(|e| e == "lock") {
+                fs::remove_file(&path)
+                    .map_err(|e| format!("File {} can not be deleted: {}", path.display(), e))?;
+            }
+        }
+        Ok(count)
+    }
+
+    // remove the least recently used entries until the cache fits in max_size
+    // the entry keep and the entries in use (locked by an install) are never removed
+    // return the removed entries
+    pub fn prune(&self, keep: &str) -> Result<Vec<CacheEntry>, String> {
+        let max_size = match self.max_size {
+            Some(max_size) => max_size,
+            None => return Ok(vec![]),
+        };
+        // an entry directory may contain several files (url renamed)
+        // they are removed together, the most recent one gives its use time
+        // (entries are sorted by key)
+        let mut keys: Vec<(String, u64, SystemTime, Vec<CacheEntry>)> = vec![];
+        for entry in self.entries() {
+            match keys.last_mut().filter(|(key, _, _, _)| *key == entry.key) {
+                Some((_, size, used, files)) => {
+                    *size += entry.size;
+                    *used = (*used).max(entry.used);
+                    files.push(entry);
+                }
+                None => keys.push((entry.key.clone(), entry.size, entry.used, vec![entry])),
+            }
+        }
+        let mut size: u64 = keys.iter().map(|(_, size, _, _)| size).sum();
+        keys.sort_by_key(|(_, _, used, _)| *used);
+        let mut removed = vec![];
+        for (key, key_size, _, files) in keys {
+            if size <= max_size {
+                break;
+            }
+            if key == keep {
+                continue;
+            }
+            if let Ok(_lock) = self.lock(&key, LockMode::Exclusive, false, "") {
+                self.remove(&key)?;
+                size -= key_size;
+                removed.extend(files);
+            }
+        }
+        Ok(removed)
+    }
+}
+
+// 500, 500K, 200M, 10G (powers of 1024)
+pub fn parse_size(s: &str) -> Option<u64> {
+    let (value, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => s.split_at(index),
+        None => (s, ""),
+    };
+    let value: u64 = value.parse().ok()?;
+    let factor: u64 = match unit {
+        "" | "B" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        _ => return None,
+    };
+    value.checked_mul(factor)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::HashAlgorithm;
+    use std::time::Duration;
+
+    fn setup(name: &str) -> Cache {
+        let dir = Path::new("target/cache").join(name);
+        if dir.exists() {
+            fs::remove_dir_all(&dir).unwrap();
+        }
+        fs::create_dir_all(&dir).unwrap();
+        Cache {
+            dir,
+            max_size: None,
+        }
+    }
+
+    fn insert(cache: &Cache, key: &str, size: usize, used: u64) {
+        cache
+            .create_entry(key, "http://localhost/a.tar.gz")
+            .unwrap();
+        let file = cache.file(key, "a.tar.gz");
+        fs::write(&file, vec![0; size]).unwrap();
+        File::options()
+            .write(true)
+            .open(&file)
+            .unwrap()
+            .set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(used))
+            .unwrap();
+    }
+
+    #[test]
+    pub fn test_key() {
+        let checksum = Checksum {
+            algorithm: HashAlgorithm::Sha256,
+            value: "407c637188d3aa862289efff5d88ac192e689350ec768203f18e72a632ba3300".to_string(),
+        };
+        assert_eq!(
+            Cache::key("http://localhost/a.tar.gz", Some(&checksum)),
+            "sha256-407c637188d3aa862289efff5d88ac192e689350ec768203f18e72a632ba3300"
+        );
+        let upper = Checksum {
+            algorithm: HashAlgorithm::Sha256,
+            value: checksum.value.to_uppercase(),
+        };
+        assert_eq!(
+            Cache::key("http://localhost/a.tar.gz", Some(&upper)),
+            Cache::key("http://localhost/a.tar.gz", Some(&checksum))
+        );
+        let key = Cache::key("http://localhost/a.tar.gz", None);
+        assert!(key.starts_with("url-"));
+        assert_eq!(key.len(), 36);
+        assert_ne!(key, Cache::key("http://localhost/b/a.tar.gz", None));
+    }
+
+    #[test]
+    pub fn test_entries_clean() {
+        let cache = setup("clean");
+        insert(&cache, "url-a", 10, 1);
+        insert(&cache, "url-b", 20, 2);
+        cache
+            .create_entry("url-c", "http://localhost/c.tar.gz")
+            .unwrap();
+        fs::write(cache.file("url-c", "c.tar.gz.part"), "").unwrap();
+        let entries = cache.entries();
+        assert_eq!(
+            entries
+                .iter()
+                .map(|e| e.key.as_str())
+                .collect::<Vec<&str>>(),
+            vec!["url-a", "url-b"]
+        );
+        assert_eq!(entries[1].size, 20);
+        assert_eq!(
+            entries[0].url,
+            Some("http://localhost/a.tar.gz".to_string())
+        );
+        drop(cache.lock("url-a", LockMode::Exclusive, false, "").unwrap());
+        assert!(cache.dir.join("url-a.lock").exists());
+        assert_eq!(cache.clean().unwrap(), 3);
+        assert!(cache.entries().is_empty());
+        assert!(!cache.dir.join("url-a.lock").exists());
+    }
+
+    #[test]
+    pub fn test_prune() {
+        let mut cache = setup("prune");
+        insert(&cache, "url-a", 10, 3);
+        insert(&cache, "url-b", 10, 1);
+        insert(&cache, "url-c", 10, 2);
+        insert(&cache, "url-d", 10, 4);
+        assert!(cache.prune("url-d").unwrap().is_empty());
+
+        cache.max_size = Some(25);
+        // url-b is the least recently used but is kept
+        let removed = cache.prune("url-b").unwrap();
+        assert_eq!(
+            removed
+                .iter()
+                .map(|e| e.key.as_str())
+                .collect::<Vec<&str>>(),
+            vec!["url-c", "url-a"]
+        );
+        assert_eq!(
+            cache
+                .entries()
+                .iter()
+                .map(|e| e.key.as_str())
+                .collect::<Vec<&str>>(),
+            vec!["url-b", "url-d"]
+        );
+
+        // url-d is used by an install
+        cache.max_size = Some(5);
+        let lock = cache.lock("url-d", LockMode::Shared, false, "").unwrap();
+        assert!(cache.prune("url-b").unwrap().is_empty());
+        drop(lock);
+        assert_eq!(cache.prune("url-b").unwrap().len(), 1);
+
+        // the files of an entry are removed together
+        let cache = Cache {
+            max_size: Some(25),
+            ..setup("prune_files")
+        };
+        insert(&cache, "url-a", 10, 1);
+        let old = cache.file("url-a", "old.tar.gz");
+        fs::write(&old, vec![0; 10]).unwrap();
+        File::options()
+            .write(true)
+            .open(&old)
+            .unwrap()
+            .set_modified(SystemTime::UNIX_EPOCH)
+            .unwrap();
+        insert(&cache, "url-b", 10, 2);
+        let removed = cache.prune("").unwrap();
+        assert_eq!(removed.len(), 2);
+        assert!(removed.iter().all(|e| e.key == "url-a"));
+        assert_eq!(cache.entries().len(), 1);
+    }
+
+    #[test]
+    pub fn test_parse_size() {
+        assert_eq!(parse_size("500"), Some(500));
+        assert_eq!(parse_size("200M"), Some(200 * 1024 * 1024));
+        assert_eq!(parse_size("10G"), Some(10 * 1024 * 1024 * 1024));
+        assert_eq!(parse_size("10T"), None);
+        assert_eq!(parse_size("G"), None);
+    }
+}
@@ -0,0 +1,141 @@
+use serde::{Deserialize, Serialize};
+use sha2::Digest;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::Path;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Checksum {
+    pub algorithm: HashAlgorithm,
+    pub value: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgorithm {
+    Sha256,
+    Sha512,
+    Blake3,
+}
+
+impl HashAlgorithm {
+    pub fn name(&self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Sha512 => "sha512",
+            HashAlgorithm::Blake3 => "blake3",
+        }
+    }
+
+    // length of the hexadecimal digest
+    pub fn hex_len(&self) -> usize {
+        match self {
+            HashAlgorithm::Sha256 => 64,
+            HashAlgorithm::Sha512 => 128,
+            HashAlgorithm::Blake3 => 64,
+        }
+    }
+
+    pub fn all() -> Vec<HashAlgorithm> {
+        vec![
+            HashAlgorithm::Sha256,
+            HashAlgorithm::Sha512,
+            HashAlgorithm::Blake3,
+        ]
+    }
+}
+
+impl Checksum {
+    // compute the checksum of a file with the given algorithm
+    pub fn compute(algorithm: HashAlgorithm, file: &Path) -> io::Result<Checksum> {
+        let mut file = File::open(file)?;
+        let value = match algorithm {
+            HashAlgorithm::Sha256 => digest_file::<sha2::Sha256>(&mut file)?,
+            HashAlgorithm::Sha512 => digest_file::<sha2::Sha512>(&mut file)?,
+            HashAlgorithm::Blake3 => {
+                let mut hasher = blake3::Hasher::new();
+                io::copy(&mut file, &mut hasher)?;
+                hasher.finalize().to_hex().to_string()
+            }
+        };
+        Ok(Checksum { algorithm, value })
+    }
+
+    // compute the checksum of the file with the same algorithm
+    // and compare it (case-insensitive) with the expected value
+    // return the actual checksum if it differs
+    pub fn verify(&self, file: &Path) -> io::Result<Result<(), Checksum>> {
+        let actual = Checksum::compute(self.algorithm, file)?;
+        if actual.value.eq_ignore_ascii_case(&self.value) {
+            Ok(Ok(()))
+        } else {
+            Ok(Err(actual))
+        }
+    }
+}
+
+fn digest_file<D: Digest>(file: &mut File) -> io::Result<String> {
+    let mut hasher = D::new();
+    let mut buffer = [0; 8192];
+    loop {
+        let n = file.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+    }
+    Ok(to_hex(&hasher.finalize()))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_compute() {
+        let file = Path::new("tests/resources/hello");
+        assert_eq!(
+            Checksum::compute(HashAlgorithm::Sha256, file).unwrap(),
+            Checksum {
+                algorithm: HashAlgorithm::Sha256,
+                value: "93f55fe7b277056b9a76454b84627510e7137db07681bd62c37d516d01294a20"
+                    .to_string()
+            }
+        );
+        assert_eq!(
+            Checksum::compute(HashAlgorithm::Sha512, file).unwrap().value,
+            "330bcc194d7efaf9ef876735995e217dfe7c23d6bade7d32f9ae326a3d87a2e8aee603e9516c016afbea24f453d156f1adcac438ec9315a5518c011744cbd611"
+        );
+        assert_eq!(
+            Checksum::compute(HashAlgorithm::Blake3, file)
+                .unwrap()
+                .value
+                .len(),
+            HashAlgorithm::Blake3.hex_len()
+        );
+    }
+
+    #[test]
+    pub fn test_verify() {
+        let file = Path::new("tests/resources/hello");
+        let checksum = Checksum {
+            algorithm: HashAlgorithm::Sha256,
+            value: "93F55FE7B277056B9A76454B84627510E7137DB07681BD62C37D516D01294A20".to_string(),
+        };
+        assert_eq!(checksum.verify(file).unwrap(), Ok(()));
+
+        let checksum = Checksum {
+            algorithm: HashAlgorithm::Sha256,
+            value: "0000000000000000000000000000000000000000000000000000000000000000".to_string(),
+        };
+        assert_eq!(
+            checksum.verify(file).unwrap().err().unwrap().value,
+            "93f55fe7b277056b9a76454b84627510e7137db07681bd62c37d516d01294a20"
+        );
+    }
+}
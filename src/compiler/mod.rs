@@ -1,4 +1,5 @@
 use super::{Command, ExtractCommand, Package, PackageDef};
+use std::time::Duration;
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct CompileError {
@@ -10,11 +11,29 @@ impl PackageDef {
     pub fn compile(&self) -> Package {
         let mut commands = vec![];
         if let Some(url) = self.url.clone() {
-            let command = Command::Download(url.clone());
+            let mut urls = vec![url.clone()];
+            urls.extend(self.mirrors.clone());
+            let command = Command::Download(urls);
             commands.push(command);
-            if let Some(extract_command) = ExtractCommand::get(&url) {
-                commands.push(extract_command);
+            if let Some(checksum) = self.checksum.clone() {
+                commands.push(Command::Verify(checksum));
             }
+            let extract_command = self.archive.clone().unwrap_or(ExtractCommand::Detect);
+            if extract_command != ExtractCommand::None {
+                commands.push(Command::Extract(extract_command));
+            }
+            if self.strip_components.is_some() || self.subdir.is_some() {
+                commands.push(Command::CurrentDir {
+                    strip_components: self.strip_components.unwrap_or(0),
+                    subdir: self.subdir.clone(),
+                });
+            }
+        }
+        if !self.env.is_empty() {
+            commands.push(Command::Env(self.env.clone()));
+        }
+        if let Some(timeout) = self.timeout {
+            commands.push(Command::Timeout(Duration::from_secs(timeout)));
         }
         if let Some(build) = self.build.clone() {
             let command = Command::Shell(build);
@@ -31,15 +50,71 @@ impl PackageDef {
 }
 
 impl ExtractCommand {
-    fn get(url: &str) -> Option<Command> {
+    pub fn all() -> Vec<ExtractCommand> {
+        vec![
+            ExtractCommand::TarGz,
+            ExtractCommand::TarXz,
+            ExtractCommand::TarBz2,
+            ExtractCommand::TarZst,
+            ExtractCommand::Tar,
+            ExtractCommand::Gz,
+            ExtractCommand::Xz,
+            ExtractCommand::Bz2,
+            ExtractCommand::Zst,
+            ExtractCommand::Zip,
+            ExtractCommand::SevenZ,
+            ExtractCommand::None,
+        ]
+    }
+
+    // value of the archive field
+    pub fn name(&self) -> &str {
+        match self {
+            ExtractCommand::TarGz => "tar.gz",
+            ExtractCommand::TarXz => "tar.xz",
+            ExtractCommand::TarBz2 => "tar.bz2",
+            ExtractCommand::TarZst => "tar.zst",
+            ExtractCommand::Tar => "tar",
+            ExtractCommand::Gz => "gz",
+            ExtractCommand::Xz => "xz",
+            ExtractCommand::Bz2 => "bz2",
+            ExtractCommand::Zst => "zst",
+            ExtractCommand::Zip => "zip",
+            ExtractCommand::SevenZ => "7z",
+            ExtractCommand::None => "none",
+            ExtractCommand::Detect => "detect",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<ExtractCommand> {
+        ExtractCommand::all().into_iter().find(|c| c.name() == name)
+    }
+
+    // guess the format from the url suffix (query string excluded)
+    pub fn from_url(url: &str) -> Option<ExtractCommand> {
+        let url = url.split(['?', '#']).next().unwrap();
         if url.ends_with(".tar.gz") || url.ends_with(".tgz") {
-            Some(Command::Extract(ExtractCommand::TarGz))
-        } else if url.ends_with(".tar.xz") {
-            Some(Command::Extract(ExtractCommand::TarXz))
-        } else if url.ends_with(".tar.bz2") {
-            Some(Command::Extract(ExtractCommand::TarBz2))
+            Some(ExtractCommand::TarGz)
+        } else if url.ends_with(".tar.xz") || url.ends_with(".txz") {
+            Some(ExtractCommand::TarXz)
+        } else if url.ends_with(".tar.bz2") || url.ends_with(".tbz2") {
+            Some(ExtractCommand::TarBz2)
+        } else if url.ends_with(".tar.zst") || url.ends_with(".tzst") {
+            Some(ExtractCommand::TarZst)
+        } else if url.ends_with(".tar") {
+            Some(ExtractCommand::Tar)
+        } else if url.ends_with(".gz") {
+            Some(ExtractCommand::Gz)
+        } else if url.ends_with(".xz") {
+            Some(ExtractCommand::Xz)
+        } else if url.ends_with(".bz2") {
+            Some(ExtractCommand::Bz2)
+        } else if url.ends_with(".zst") {
+            Some(ExtractCommand::Zst)
         } else if url.ends_with(".zip") {
-            Some(Command::Extract(ExtractCommand::Zip))
+            Some(ExtractCommand::Zip)
+        } else if url.ends_with(".7z") {
+            Some(ExtractCommand::SevenZ)
         } else {
             None
         }
@@ -48,12 +123,74 @@ impl ExtractCommand {
 
 #[cfg(test)]
 pub mod tests {
-    use super::super::{my_package_def, mypackage};
+    use super::super::{my_package_def, mypackage, Command, ExtractCommand};
+    use std::time::Duration;
 
     #[test]
     pub fn test_url_only() {
         assert_eq!(my_package_def().compile(), mypackage())
     }
+
+    #[test]
+    pub fn test_build_timeout() {
+        let mut package_def = my_package_def();
+        package_def.url = None;
+        package_def.timeout = Some(60);
+        package_def.build = Some("make\n".to_string());
+        assert_eq!(
+            package_def.compile().commands,
+            vec![
+                Command::Timeout(Duration::from_secs(60)),
+                Command::Shell("make\n".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    pub fn test_archive_none() {
+        let mut package_def = my_package_def();
+        package_def.archive = Some(ExtractCommand::None);
+        assert_eq!(
+            package_def.compile().commands,
+            vec![
+                Command::Download(vec![package_def.url.clone().unwrap()]),
+                Command::Copy()
+            ]
+        );
+    }
+
+    #[test]
+    pub fn test_extract_command() {
+        assert_eq!(
+            ExtractCommand::from_url("https://ziglang.org/zig-0.9.1.tar.xz"),
+            Some(ExtractCommand::TarXz)
+        );
+        assert_eq!(
+            ExtractCommand::from_url("https://example.com/zstd-1.5.2.tar.zst"),
+            Some(ExtractCommand::TarZst)
+        );
+        assert_eq!(
+            ExtractCommand::from_url("https://example.com/src-1.0.tar"),
+            Some(ExtractCommand::Tar)
+        );
+        assert_eq!(
+            ExtractCommand::from_url("https://example.com/jq-1.6.gz"),
+            Some(ExtractCommand::Gz)
+        );
+        assert_eq!(
+            ExtractCommand::from_url("https://example.com/tool-1.0.xz"),
+            Some(ExtractCommand::Xz)
+        );
+        assert_eq!(
+            ExtractCommand::from_url("https://example.com/tool-1.0.7z"),
+            Some(ExtractCommand::SevenZ)
+        );
+        assert_eq!(
+            ExtractCommand::from_url("https://example.com/tool-1.0.zip?token=xxx"),
+            Some(ExtractCommand::Zip)
+        );
+        assert_eq!(ExtractCommand::from_url("https://example.com/tool"), None);
+    }
     //
     // #[test]
     // pub fn test_url_plus_build() {
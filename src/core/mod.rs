@@ -1,3 +1,7 @@
+use crate::Checksum;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct Package {
     pub id: String,
@@ -6,18 +10,52 @@ pub struct Package {
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum Command {
-    Download(String),
+    Download(Vec<String>), // url and its mirrors, tried in order
+    Verify(Checksum),      // check the downloaded file against its expected digest
     Extract(ExtractCommand),
+    // current directory set from the extract directory
+    // instead of guessed after the extraction
+    CurrentDir {
+        strip_components: usize,
+        subdir: Option<String>,
+    },
     Copy(), // "hardcopy" copy file from current directory to package directory
+    Env(Vec<(String, String)>), // variables of the following build scripts
+    Timeout(Duration), // maximum duration of the following build scripts
     Shell(String),
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum ExtractCommand {
+    #[serde(rename = "tar.gz")]
     TarGz,
+    #[serde(rename = "tar.xz")]
     TarXz,
+    #[serde(rename = "tar.bz2")]
     TarBz2,
+    #[serde(rename = "tar.zst")]
+    TarZst,
+    #[serde(rename = "tar")]
+    Tar,
+    #[serde(rename = "gz")]
+    Gz, // single compressed file
+    #[serde(rename = "xz")]
+    Xz, // single compressed file
+    #[serde(rename = "bz2")]
+    Bz2, // single compressed file
+    #[serde(rename = "zst")]
+    Zst, // single compressed file
+    #[serde(rename = "zip")]
     Zip,
+    #[serde(rename = "7z")]
+    SevenZ,
+    // the downloaded file is used as is
+    #[serde(rename = "none")]
+    None,
+    // format detected from the downloaded file (magic bytes)
+    // or from the url suffix
+    #[serde(skip)]
+    Detect,
 }
 
 #[cfg(test)]
@@ -25,10 +63,10 @@ pub fn mypackage() -> Package {
     Package {
         id: "mypackage@0.1.0".to_string(),
         commands: vec![
-            Command::Download(
+            Command::Download(vec![
                 "http://localhost:8000/mypackage-0.1.0-x86_64-linux.tar.gz".to_string(),
-            ),
-            Command::Extract(ExtractCommand::TarGz),
+            ]),
+            Command::Extract(ExtractCommand::Detect),
             Command::Copy(),
         ],
     }
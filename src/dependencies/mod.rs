@@ -1,9 +1,9 @@
-use crate::PackageDef;
+use crate::{PackageDef, Version, VersionReq};
 
 type DependencyError = String;
 
 // Resolve dependencies
-// the dependency must exist and be  unique (one unique version)
+// the dependency must exist, the newest version matching the query is used
 // there must be no cycle
 pub fn resolve(
     packages_defs: &Vec<PackageDef>,
@@ -15,27 +15,233 @@ pub fn resolve(
             dependencies.push((package_def.id(), p))
         }
     }
+    install_plan(packages_defs, packages_defs)?;
 
     Ok(dependencies)
 }
 
+// Return the packages to install, including their transitive dependencies
+// sorted so that each package comes after all its dependencies
+pub fn install_plan(
+    package_defs: &[PackageDef],
+    packages: &[PackageDef],
+) -> Result<Vec<PackageDef>, DependencyError> {
+    let mut plan = vec![];
+    for package in packages {
+        visit(package_defs, package, &mut vec![], &mut plan)?;
+    }
+    Ok(plan)
+}
+
+// Return the direct and transitive dependencies of a package
+// from the resolved dependencies (package id, dependency)
+pub fn transitive_dependencies(
+    package_id: &str,
+    dependencies: &[(String, PackageDef)],
+) -> Vec<PackageDef> {
+    let mut result: Vec<PackageDef> = vec![];
+    let mut queue = vec![package_id.to_string()];
+    while let Some(id) = queue.pop() {
+        for (_, dependency) in dependencies.iter().filter(|(p, _)| *p == id) {
+            if !result.contains(dependency) {
+                queue.push(dependency.id());
+                result.push(dependency.clone());
+            }
+        }
+    }
+    result
+}
+
+// depth-first traversal
+// path contains the ids of the packages being visited, from the root
+fn visit(
+    package_defs: &[PackageDef],
+    package: &PackageDef,
+    path: &mut Vec<String>,
+    plan: &mut Vec<PackageDef>,
+) -> Result<(), DependencyError> {
+    if plan.contains(package) {
+        return Ok(());
+    }
+    if let Some(index) = path.iter().position(|id| *id == package.id()) {
+        let mut cycle = path[index..].to_vec();
+        cycle.push(package.id());
+        return Err(format!("Dependency cycle detected: {}", cycle.join(" -> ")));
+    }
+    path.push(package.id());
+    for query in &package.depends {
+        let dependency = find_package(package_defs, query)?;
+        visit(package_defs, &dependency, path, plan)?;
+    }
+    path.pop();
+    plan.push(package.clone());
+    Ok(())
+}
+
+// the query is NAME, NAME@VERSION or NAME followed by version constraints
+// (NAME >=2.7,<3)
+// the newest matching version is selected
 fn find_package(package_defs: &[PackageDef], query: &str) -> Result<PackageDef, DependencyError> {
-    let packages = package_defs
+    let (name, version_req) = match query.split_once(' ') {
+        Some((name, constraints)) => (name, Some(VersionReq::parse(constraints.trim())?)),
+        None => (query, None),
+    };
+    let candidates = package_defs
         .iter()
-        .filter(|p| {
-            query == p.name.as_str() || query == format!("{}@{}", p.name, p.version).as_str()
-        })
+        .filter(|p| name == p.name.as_str() || name == p.id().as_str())
         .cloned()
         .collect::<Vec<PackageDef>>();
 
-    if packages.is_empty() {
-        Err(format!("Package dependency {} can not be found", query))
-    } else if packages.len() > 1 {
-        Err(format!(
-            "Package dependency {} can not be uniquely resolved",
-            query
-        ))
-    } else {
-        Ok(packages.get(0).unwrap().clone())
+    if candidates.is_empty() {
+        return Err(format!("Package dependency {} can not be found", query));
+    }
+
+    let mut packages = vec![];
+    let mut rejected = vec![];
+    for package in candidates {
+        match version_req
+            .as_ref()
+            .and_then(|r| r.unsatisfied(&package.version))
+        {
+            None => packages.push(package),
+            Some(comparator) => rejected.push(format!(
+                "  {}: does not satisfy {}",
+                package.id(),
+                comparator
+            )),
+        }
+    }
+    match packages
+        .into_iter()
+        .max_by(|a, b| Version::parse(&a.version).cmp(&Version::parse(&b.version)))
+    {
+        Some(package) => Ok(package),
+        None => Err(format!(
+            "Package dependency {} can not be resolved\n{}",
+            query,
+            rejected.join("\n")
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn package_def(name: &str, depends: &[&str]) -> PackageDef {
+        package_def_version(name, "1.0.0", depends)
+    }
+
+    fn package_def_version(name: &str, version: &str, depends: &[&str]) -> PackageDef {
+        PackageDef {
+            name: name.to_string(),
+            version: version.to_string(),
+            depends: depends.iter().map(|s| s.to_string()).collect(),
+            url: None,
+            mirrors: vec![],
+            checksum: None,
+            archive: None,
+            strip_components: None,
+            subdir: None,
+            env: vec![],
+            timeout: None,
+            build: Some("true".to_string()),
+        }
+    }
+
+    fn ids(packages: &[PackageDef]) -> Vec<String> {
+        packages.iter().map(|p| p.id()).collect()
+    }
+
+    #[test]
+    pub fn test_install_plan() {
+        let package_defs = vec![
+            package_def("a", &["b", "c"]),
+            package_def("b", &["d"]),
+            package_def("c", &["d@1.0.0"]),
+            package_def("d", &[]),
+        ];
+        assert_eq!(
+            ids(&install_plan(&package_defs, &[package_defs[0].clone()]).unwrap()),
+            vec!["d@1.0.0", "b@1.0.0", "c@1.0.0", "a@1.0.0"]
+        );
+        assert_eq!(
+            ids(&install_plan(&package_defs, &package_defs).unwrap()),
+            vec!["d@1.0.0", "b@1.0.0", "c@1.0.0", "a@1.0.0"]
+        );
+    }
+
+    #[test]
+    pub fn test_transitive_dependencies() {
+        let package_defs = vec![
+            package_def("a", &["b", "c"]),
+            package_def("b", &["d"]),
+            package_def("c", &["d@1.0.0"]),
+            package_def("d", &[]),
+        ];
+        let dependencies = resolve(&package_defs).unwrap();
+        assert_eq!(
+            ids(&transitive_dependencies("a@1.0.0", &dependencies)),
+            vec!["b@1.0.0", "c@1.0.0", "d@1.0.0"]
+        );
+        assert!(transitive_dependencies("d@1.0.0", &dependencies).is_empty());
+    }
+
+    #[test]
+    pub fn test_cycle() {
+        let package_defs = vec![
+            package_def("a", &["b"]),
+            package_def("b", &["c"]),
+            package_def("c", &["a"]),
+        ];
+        assert_eq!(
+            resolve(&package_defs).err().unwrap(),
+            "Dependency cycle detected: a@1.0.0 -> b@1.0.0 -> c@1.0.0 -> a@1.0.0"
+        );
+        assert_eq!(
+            install_plan(&package_defs, &[package_defs[1].clone()])
+                .err()
+                .unwrap(),
+            "Dependency cycle detected: b@1.0.0 -> c@1.0.0 -> a@1.0.0 -> b@1.0.0"
+        );
+    }
+
+    #[test]
+    pub fn test_dependency_not_found() {
+        let package_defs = vec![package_def("a", &["b"])];
+        assert_eq!(
+            install_plan(&package_defs, &package_defs).err().unwrap(),
+            "Package dependency b can not be found"
+        );
+    }
+
+    #[test]
+    pub fn test_version_constraints() {
+        let package_defs = vec![
+            package_def_version("ruby", "2.6.0", &[]),
+            package_def_version("ruby", "2.7.0", &[]),
+            package_def_version("ruby", "2.7.5", &[]),
+            package_def_version("ruby", "3.0.1", &[]),
+        ];
+        assert_eq!(
+            find_package(&package_defs, "ruby >=2.7,<3").unwrap().id(),
+            "ruby@2.7.5"
+        );
+        assert_eq!(
+            find_package(&package_defs, "ruby").unwrap().id(),
+            "ruby@3.0.1"
+        );
+        assert_eq!(
+            find_package(&package_defs, "ruby@2.7.0").unwrap().id(),
+            "ruby@2.7.0"
+        );
+        assert_eq!(
+            find_package(&package_defs, "ruby ^4").err().unwrap(),
+            r#"Package dependency ruby ^4 can not be resolved
+  ruby@2.6.0: does not satisfy ^4
+  ruby@2.7.0: does not satisfy ^4
+  ruby@2.7.5: does not satisfy ^4
+  ruby@3.0.1: does not satisfy ^4"#
+        );
     }
 }
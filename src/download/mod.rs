@@ -0,0 +1,1111 @@
+use humansize::{file_size_opts, FileSize};
+use reqwest::header::{CONTENT_RANGE, ETAG, IF_RANGE, LAST_MODIFIED, RANGE};
+use reqwest::StatusCode;
+use std::ffi::OsString;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io::{IsTerminal, Read, Write};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DownloadError {
+    pub message: String,
+    pub retry: bool, // transient error (connection, server error)
+}
+
+impl DownloadError {
+    fn fatal(message: String) -> DownloadError {
+        DownloadError {
+            message,
+            retry: false,
+        }
+    }
+
+    fn transient(message: String) -> DownloadError {
+        DownloadError {
+            message,
+            retry: true,
+        }
+    }
+}
+
+// Download the first available url into file
+// each url is retried on transient errors, waiting delay, 2 * delay, 4 * delay...
+// before falling back to the next one
+// return the url used and the number of bytes that were already downloaded
+pub fn download_mirrors(
+    urls: &[String],
+    file: &Path,
+    retries: u32,
+    delay: Duration,
+    prefix: &str,
+) -> Result<(String, u64), String> {
+    let mut message = "No url to download".to_string();
+    for (i, url) in urls.iter().enumerate() {
+        if i > 0 {
+            eprint_line(&format!("{}{}, trying mirror <{}>", prefix, message, url));
+        }
+        let mut attempt = 0;
+        loop {
+            match download(url, file) {
+                Ok(offset) => return Ok((url.clone(), offset)),
+                Err(e) => {
+                    message = e.message;
+                    if !e.retry || attempt >= retries {
+                        break;
+                    }
+                    let wait = delay * 2u32.pow(attempt);
+                    eprint_line(&format!(
+                        "{}{}, retrying in {}s",
+                        prefix,
+                        message,
+                        wait.as_secs_f32()
+                    ));
+                    thread::sleep(wait);
+                    attempt += 1;
+                }
+            }
+        }
+    }
+    Err(message)
+}
+
+// path of a file:// url (file:///path or file://localhost/path)
+// percent-escapes (%20) are decoded
+pub fn local_path(url: &str) -> Option<PathBuf> {
+    let path = url.strip_prefix("file://")?;
+    let path = path.strip_prefix("localhost").unwrap_or(path);
+    if !path.starts_with('/') {
+        return None;
+    }
+    Some(PathBuf::from(OsString::from_vec(decode_path(path))))
+}
+
+// local sources are relative to base_dir and returned as absolute file:// urls,
+// other urls are unchanged:
+// - plain paths: dist/a.tar.gz, /mnt/nfs/a.tar.gz
+// - file: followed by a path, without //: file:dist/a.tar.gz, file:/mnt/nfs/a.tar.gz
+// - file:// urls with an absolute path: file:///mnt/nfs/a.tar.gz
+// file://dist/a.tar.gz is an error, dist would be the host of the url
+pub fn source_url(url: &str, base_dir: &Path) -> Result<String, String> {
+    let path = match url.strip_prefix("file:") {
+        Some(rest) if rest.starts_with("//") => {
+            return match local_path(url) {
+                Some(path) => Ok(file_url(&path)),
+                None => Err(format!(
+                    "Invalid url <{}>: a file:// url needs an absolute path (file:///path), a relative path is written file:path",
+                    url
+                )),
+            };
+        }
+        Some(rest) => PathBuf::from(OsString::from_vec(decode_path(rest))),
+        None if url.contains("://") => return Ok(url.to_string()),
+        None => PathBuf::from(url),
+    };
+    Ok(file_url(&base_dir.join(path)))
+}
+
+// file:// url of an absolute path, escaped so that local_path returns the path
+fn file_url(path: &Path) -> String {
+    let mut url = "file://".to_string();
+    for byte in path.as_os_str().as_bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~!$&'()*+,;=:@/".contains(byte) {
+            url.push(*byte as char);
+        } else {
+            url.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    url
+}
+
+// %XX escapes are decoded, an invalid escape is kept as is
+fn decode_path(path: &str) -> Vec<u8> {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = (bytes[i] == b'%')
+            .then(|| bytes.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    decoded
+}
+
+// Download url into file
+// a file:// url is hard-linked (or copied) into file
+// a hard link is the source itself: file must only be read, never written to
+// the content is first written to <file>.part and renamed once complete
+// an existing .part file (interrupted download) is resumed with a Range request
+// if the server supports it and the remote file has not changed:
+// the validator of the remote file (ETag or Last-Modified, and size) is kept
+// in <file>.part.info, sent with If-Range and compared with the Content-Range
+// return the number of bytes that were already downloaded
+pub fn download(url: &str, file: &Path) -> Result<u64, DownloadError> {
+    let part_file = part_file(file);
+    if let Some(path) = local_path(url) {
+        return copy_local(&path, file, &part_file).map(|_| 0);
+    }
+    if url.starts_with("file:") {
+        return Err(DownloadError::fatal(format!("Invalid url <{}>", url)));
+    }
+    let info_file = part_info_file(file);
+    let validator = Validator::read(&info_file).filter(|v| v.can_resume());
+    // without validator, the partial file can not be trusted
+    let offset = match validator {
+        Some(_) => fs::metadata(&part_file).map(|m| m.len()).unwrap_or(0),
+        None => 0,
+    };
+
+    // no timeout, archives can be very large
+    let client = reqwest::blocking::Client::builder()
+        .timeout(None)
+        .build()
+        .map_err(|e| DownloadError::fatal(e.to_string()))?;
+    let mut request = client.get(url);
+    if offset > 0 {
+        request = request.header(RANGE, format!("bytes={}-", offset));
+        if let Some(if_range) = validator.as_ref().and_then(|v| v.if_range()) {
+            request = request.header(IF_RANGE, if_range);
+        }
+    }
+    let mut response = request
+        .send()
+        .map_err(|e| DownloadError::transient(e.to_string()))?;
+
+    let status = response.status();
+    let same_total = match validator.as_ref().and_then(|v| v.total) {
+        Some(total) => range_total(&response) == Some(total),
+        None => true,
+    };
+    let (mut dest, offset) = if offset > 0
+        && status == StatusCode::PARTIAL_CONTENT
+        && range_start(&response) == Some(offset)
+        && same_total
+    {
+        let dest = OpenOptions::new()
+            .append(true)
+            .open(&part_file)
+            .map_err(|e| {
+                DownloadError::fatal(format!("Can not open {}: {}", part_file.display(), e))
+            })?;
+        (dest, offset)
+    } else if status == StatusCode::OK {
+        // the server does not support range requests or the file has changed,
+        // start again
+        let dest = File::create(&part_file).map_err(|e| {
+            DownloadError::fatal(format!("Can not create {}: {}", part_file.display(), e))
+        })?;
+        Validator::from_response(&response)
+            .write(&info_file)
+            .map_err(|e| {
+                DownloadError::fatal(format!("Can not write {}: {}", info_file.display(), e))
+            })?;
+        (dest, 0)
+    } else if offset > 0
+        && (status == StatusCode::PARTIAL_CONTENT || status == StatusCode::RANGE_NOT_SATISFIABLE)
+    {
+        // the partial file can not be resumed (unexpected range or size)
+        // on other errors, it is kept to be resumed by the next attempt
+        for f in [&part_file, &info_file] {
+            fs::remove_file(f).map_err(|e| {
+                DownloadError::fatal(format!("Can not delete {}: {}", f.display(), e))
+            })?;
+        }
+        return download(url, file);
+    } else {
+        let message = format!("Url <{}> can not be downloaded: status {}", url, status);
+        let transient = status.is_server_error()
+            || status == StatusCode::TOO_MANY_REQUESTS
+            || status == StatusCode::REQUEST_TIMEOUT;
+        return Err(DownloadError {
+            message,
+            retry: transient,
+        });
+    };
+
+    // the partial file is kept on error to be resumed later
+    let total = response.content_length().map(|length| offset + length);
+    let mut progress = Progress::new(file, offset, total);
+    let mut buffer = [0; 64 * 1024];
+    loop {
+        let n = match response.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) => {
+                return Err(DownloadError::transient(format!(
+                    "Error downloading - {}",
+                    e
+                )));
+            }
+        };
+        if let Err(e) = dest.write_all(&buffer[..n]) {
+            return Err(DownloadError::fatal(format!(
+                "Can not write {}: {}",
+                part_file.display(),
+                e
+            )));
+        }
+        progress.update(n as u64);
+    }
+    drop(progress);
+    fs::rename(&part_file, file).map_err(|e| {
+        DownloadError::fatal(format!(
+            "Can not rename {} to {}: {}",
+            part_file.display(),
+            file.display(),
+            e
+        ))
+    })?;
+    let _ = fs::remove_file(&info_file);
+    Ok(offset)
+}
+
+// the copy keeps the modification time of the source, see is_up_to_date
+fn copy_local(path: &Path, file: &Path, part_file: &Path) -> Result<(), DownloadError> {
+    if !path.is_file() {
+        return Err(DownloadError::fatal(format!(
+            "File {} does not exist",
+            path.display()
+        )));
+    }
+    if fs::hard_link(path, file).is_ok() {
+        return Ok(());
+    }
+    let copy = || -> std::io::Result<()> {
+        fs::copy(path, part_file)?;
+        let modified = fs::metadata(path)?.modified()?;
+        File::options()
+            .write(true)
+            .open(part_file)?
+            .set_modified(modified)
+    };
+    copy().map_err(|e| {
+        DownloadError::fatal(format!(
+            "Can not copy {} to {}: {}",
+            path.display(),
+            part_file.display(),
+            e
+        ))
+    })?;
+    fs::rename(part_file, file).map_err(|e| {
+        DownloadError::fatal(format!(
+            "Can not rename {} to {}: {}",
+            part_file.display(),
+            file.display(),
+            e
+        ))
+    })
+}
+
+// file is a hard link to path
+pub fn is_same_file(path: &Path, file: &Path) -> bool {
+    match (fs::metadata(path), fs::metadata(file)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+
+// file is a link or a copy of the local source path, it does not need to be copied again
+pub fn is_up_to_date(path: &Path, file: &Path) -> bool {
+    if is_same_file(path, file) {
+        return true;
+    }
+    match (fs::metadata(path), fs::metadata(file)) {
+        (Ok(a), Ok(b)) => {
+            a.is_file()
+                && a.len() == b.len()
+                && matches!((a.modified(), b.modified()), (Ok(x), Ok(y)) if x == y)
+        }
+        _ => false,
+    }
+}
+
+pub fn part_file(file: &Path) -> PathBuf {
+    let mut name = file.file_name().unwrap().to_os_string();
+    name.push(".part");
+    file.with_file_name(name)
+}
+
+pub fn part_info_file(file: &Path) -> PathBuf {
+    let mut name = file.file_name().unwrap().to_os_string();
+    name.push(".part.info");
+    file.with_file_name(name)
+}
+
+// identifies the remote file of a partial download
+// so that it is not resumed from a different file (updated, other mirror)
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+struct Validator {
+    total: Option<u64>,
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+impl Validator {
+    fn from_response(response: &reqwest::blocking::Response) -> Validator {
+        let header = |name| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(|value| value.to_string())
+        };
+        Validator {
+            total: response.content_length(),
+            etag: header(ETAG),
+            last_modified: header(LAST_MODIFIED),
+        }
+    }
+
+    // total=10, etag="abc", last-modified=Wed, 01 Jun 2022 12:00:00 GMT (one per line)
+    fn read(file: &Path) -> Option<Validator> {
+        let content = fs::read_to_string(file).ok()?;
+        let mut validator = Validator::default();
+        for line in content.lines() {
+            match line.split_once('=') {
+                Some(("total", value)) => validator.total = value.parse().ok(),
+                Some(("etag", value)) => validator.etag = Some(value.to_string()),
+                Some(("last-modified", value)) => validator.last_modified = Some(value.to_string()),
+                _ => {}
+            }
+        }
+        Some(validator)
+    }
+
+    fn write(&self, file: &Path) -> std::io::Result<()> {
+        let mut content = String::new();
+        if let Some(total) = self.total {
+            content.push_str(&format!("total={}\n", total));
+        }
+        if let Some(etag) = &self.etag {
+            content.push_str(&format!("etag={}\n", etag));
+        }
+        if let Some(last_modified) = &self.last_modified {
+            content.push_str(&format!("last-modified={}\n", last_modified));
+        }
+        fs::write(file, content)
+    }
+
+    // a weak etag (W/"...") can not be used with If-Range
+    fn if_range(&self) -> Option<&str> {
+        match &self.etag {
+            Some(etag) if !etag.starts_with("W/") => Some(etag),
+            _ => self.last_modified.as_deref(),
+        }
+    }
+
+    fn can_resume(&self) -> bool {
+        self.if_range().is_some() || self.total.is_some()
+    }
+}
+
+// progress of a download on stderr
+// on a terminal, the line of the download is updated in the shared display,
+// otherwise a line is printed from time to time
+// the size is known from the Content-Length header, or a spinner is displayed
+struct Progress {
+    name: String,
+    downloaded: u64,
+    total: Option<u64>,
+    resumed_at: u64, // not counted in the rate
+    start: Instant,
+    last_report: Instant,
+    display_id: Option<usize>, // on a terminal, until finished
+}
+
+const TERMINAL_REFRESH: Duration = Duration::from_millis(200);
+const LOG_REFRESH: Duration = Duration::from_secs(10);
+
+impl Progress {
+    fn new(file: &Path, offset: u64, total: Option<u64>) -> Progress {
+        let now = Instant::now();
+        let display_id = if std::io::stderr().is_terminal() {
+            Some(DISPLAY.lock().unwrap().add())
+        } else {
+            None
+        };
+        Progress {
+            name: file.file_name().unwrap().to_string_lossy().to_string(),
+            downloaded: offset,
+            total,
+            resumed_at: offset,
+            start: now,
+            last_report: now,
+            display_id,
+        }
+    }
+
+    fn update(&mut self, n: u64) {
+        self.downloaded += n;
+        let refresh = match self.display_id {
+            Some(_) => TERMINAL_REFRESH,
+            None => LOG_REFRESH,
+        };
+        if self.last_report.elapsed() < refresh {
+            return;
+        }
+        self.last_report = Instant::now();
+        let line = progress_line(
+            &self.name,
+            self.downloaded - self.resumed_at,
+            self.downloaded,
+            self.total,
+            self.start.elapsed(),
+            self.display_id.is_some(),
+        );
+        match self.display_id {
+            Some(id) => {
+                let mut display = DISPLAY.lock().unwrap();
+                display.set(id, line);
+                display.redraw(&mut std::io::stderr(), terminal_width());
+            }
+            None => eprintln!("{}", line),
+        }
+    }
+}
+
+// the progress line is erased, the caller displays the result
+impl Drop for Progress {
+    fn drop(&mut self) {
+        if let Some(id) = self.display_id.take() {
+            let mut display = DISPLAY.lock().unwrap();
+            display.remove(id);
+            display.redraw(&mut std::io::stderr(), terminal_width());
+        }
+    }
+}
+
+// progress lines of the running downloads (several are run in parallel)
+// drawn at the bottom of the terminal, one line per download
+static DISPLAY: Mutex<Display> = Mutex::new(Display::new());
+
+#[derive(Debug)]
+struct Display {
+    lines: Vec<(usize, String)>, // id of the download, progress line
+    drawn: usize,                // number of lines on the terminal
+    next_id: usize,
+}
+
+impl Display {
+    const fn new() -> Display {
+        Display {
+            lines: vec![],
+            drawn: 0,
+            next_id: 0,
+        }
+    }
+
+    fn add(&mut self) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.lines.push((id, "".to_string()));
+        id
+    }
+
+    fn set(&mut self, id: usize, line: String) {
+        if let Some(entry) = self.lines.iter_mut().find(|(i, _)| *i == id) {
+            entry.1 = line;
+        }
+    }
+
+    fn remove(&mut self, id: usize) {
+        self.lines.retain(|(i, _)| *i != id);
+    }
+
+    // back to the first drawn line, the lines are cleared
+    fn erase(&mut self, out: &mut dyn Write) {
+        if self.drawn > 0 {
+            let _ = write!(out, "\x1b[{}A\r\x1b[J", self.drawn);
+            let _ = out.flush();
+            self.drawn = 0;
+        }
+    }
+
+    // back to the first drawn line, the lines are overwritten
+    // and the remaining ones (finished downloads) cleared
+    // lines are truncated to the width of the terminal so that they do not wrap
+    fn redraw(&mut self, out: &mut dyn Write, width: usize) {
+        let mut content = String::new();
+        let previous = self.drawn;
+        if previous > 0 {
+            content.push_str(&format!("\x1b[{}A\r", previous));
+        }
+        self.drawn = 0;
+        for (_, line) in &self.lines {
+            if line.is_empty() {
+                continue;
+            }
+            let line = line
+                .chars()
+                .take(width.saturating_sub(1))
+                .collect::<String>();
+            content.push_str(&format!("{}\x1b[K\n", line));
+            self.drawn += 1;
+        }
+        if previous > 0 {
+            content.push_str("\x1b[J");
+        }
+        let _ = out.write_all(content.as_bytes());
+        let _ = out.flush();
+    }
+}
+
+// lines printed while downloads are running go above their progress lines
+// when stdout is not the terminal of the progress lines (redirected), they
+// are left as is
+pub fn print_line(line: &str) {
+    let mut display = DISPLAY.lock().unwrap();
+    if !is_display_terminal(libc::STDOUT_FILENO) {
+        println!("{}", line);
+        let _ = std::io::stdout().flush();
+        return;
+    }
+    display.erase(&mut std::io::stderr());
+    println!("{}", line);
+    let _ = std::io::stdout().flush();
+    display.redraw(&mut std::io::stderr(), terminal_width());
+}
+
+pub fn eprint_line(line: &str) {
+    let mut display = DISPLAY.lock().unwrap();
+    display.erase(&mut std::io::stderr());
+    eprintln!("{}", line);
+    display.redraw(&mut std::io::stderr(), terminal_width());
+}
+
+// whether fd is the terminal of the progress lines (stderr)
+fn is_display_terminal(fd: libc::c_int) -> bool {
+    let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+    let mut stderr_stat: libc::stat = unsafe { std::mem::zeroed() };
+    let terminals = unsafe {
+        libc::isatty(fd) == 1
+            && libc::fstat(fd, &mut stat) == 0
+            && libc::fstat(libc::STDERR_FILENO, &mut stderr_stat) == 0
+    };
+    terminals && stat.st_rdev == stderr_stat.st_rdev
+}
+
+// columns of the terminal (stderr), 80 if unknown
+fn terminal_width() -> usize {
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    let result = unsafe { libc::ioctl(libc::STDERR_FILENO, libc::TIOCGWINSZ, &mut size) };
+    if result == 0 && size.ws_col > 0 {
+        size.ws_col as usize
+    } else {
+        80
+    }
+}
+
+// foo.tar.gz [=========>          ] 48% 4.80 MB / 10 MB 1.20 MB/s ETA 5s
+// Downloading foo.tar.gz: 48% 4.80 MB / 10 MB, 1.20 MB/s, ETA 5s
+// session is the number of bytes downloaded by this request (for the rate)
+fn progress_line(
+    name: &str,
+    session: u64,
+    downloaded: u64,
+    total: Option<u64>,
+    elapsed: Duration,
+    terminal: bool,
+) -> String {
+    let bytes_per_sec = rate_per_sec(session, elapsed);
+    let rate = format!("{}/s", size(bytes_per_sec));
+    match total {
+        Some(total) if total > 0 => {
+            let percent = (downloaded.min(total) * 100 / total) as usize;
+            let eta = match total.saturating_sub(downloaded).checked_div(bytes_per_sec) {
+                Some(secs) => format!("ETA {}", duration(secs)),
+                None => "ETA -".to_string(),
+            };
+            let sizes = format!("{} / {}", size(downloaded), size(total));
+            if terminal {
+                let width = 20;
+                let filled = percent * width / 100;
+                format!(
+                    "{} [{}{}] {:>3}% {} {} {}",
+                    name,
+                    "=".repeat(filled),
+                    " ".repeat(width - filled),
+                    percent,
+                    sizes,
+                    rate,
+                    eta
+                )
+            } else {
+                format!(
+                    "Downloading {}: {}% {}, {}, {}",
+                    name, percent, sizes, rate, eta
+                )
+            }
+        }
+        _ => {
+            if terminal {
+                let spinner = ['|', '/', '-', '\\'][(elapsed.as_millis() / 200) as usize % 4];
+                format!("{} {} {} {}", name, spinner, size(downloaded), rate)
+            } else {
+                format!("Downloading {}: {}, {}", name, size(downloaded), rate)
+            }
+        }
+    }
+}
+
+fn rate_per_sec(bytes: u64, elapsed: Duration) -> u64 {
+    (bytes as f64 / elapsed.as_secs_f64().max(0.001)) as u64
+}
+
+fn size(bytes: u64) -> String {
+    bytes
+        .file_size(file_size_opts::CONVENTIONAL)
+        .unwrap_or_else(|_| format!("{} B", bytes))
+}
+
+// 5s, 2m05s, 1h02m
+fn duration(secs: u64) -> String {
+    if secs < 60 {
+        format!("{}s", secs)
+    } else if secs < 3600 {
+        format!("{}m{:02}s", secs / 60, secs % 60)
+    } else {
+        format!("{}h{:02}m", secs / 3600, secs % 3600 / 60)
+    }
+}
+
+// start of the range from the Content-Range header (bytes 100-199/200)
+fn range_start(response: &reqwest::blocking::Response) -> Option<u64> {
+    let value = response.headers().get(CONTENT_RANGE)?.to_str().ok()?;
+    let range = value.strip_prefix("bytes ")?;
+    let (start, _) = range.split_once('-')?;
+    start.parse().ok()
+}
+
+// total size from the Content-Range header (bytes 100-199/200)
+fn range_total(response: &reqwest::blocking::Response) -> Option<u64> {
+    let value = response.headers().get(CONTENT_RANGE)?.to_str().ok()?;
+    let (_, total) = value.rsplit_once('/')?;
+    total.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::os::unix::io::AsRawFd;
+    use std::thread;
+
+    // serve content, honouring the Range header if range is true
+    fn serve(content: &'static [u8], range: bool) -> String {
+        serve_etag(content, range, None)
+    }
+
+    // with an etag, a range is only served if If-Range matches it
+    fn serve_etag(content: &'static [u8], range: bool, etag: Option<&'static str>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut start = 0;
+                let mut if_range = None;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line == "\r\n" {
+                        break;
+                    }
+                    let lower = line.to_lowercase();
+                    if let Some(value) = lower.strip_prefix("range: bytes=") {
+                        if range {
+                            start = value.trim().trim_end_matches('-').parse().unwrap();
+                        }
+                    }
+                    if lower.starts_with("if-range: ") {
+                        if_range = Some(line["if-range: ".len()..].trim().to_string());
+                    }
+                }
+                if if_range.is_some() && if_range.as_deref() != etag {
+                    start = 0;
+                }
+                let body = &content[start..];
+                let etag_header = match etag {
+                    Some(etag) => format!("ETag: {}\r\n", etag),
+                    None => "".to_string(),
+                };
+                let header = if start > 0 {
+                    format!(
+                        "HTTP/1.1 206 Partial Content\r\n{}Content-Range: bytes {}-{}/{}\r\nContent-Length: {}\r\n\r\n",
+                        etag_header,
+                        start,
+                        content.len() - 1,
+                        content.len(),
+                        body.len()
+                    )
+                } else {
+                    format!(
+                        "HTTP/1.1 200 OK\r\n{}Content-Length: {}\r\n\r\n",
+                        etag_header,
+                        body.len()
+                    )
+                };
+                stream.write_all(header.as_bytes()).unwrap();
+                stream.write_all(body).unwrap();
+            }
+        });
+        format!("http://127.0.0.1:{}/file.tar.gz", port)
+    }
+
+    // the request is answered with status, without body
+    fn serve_status(status: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line == "\r\n" {
+                        break;
+                    }
+                }
+                let header = format!("HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status);
+                stream.write_all(header.as_bytes()).unwrap();
+            }
+        });
+        format!("http://127.0.0.1:{}/file.tar.gz", port)
+    }
+
+    fn setup(name: &str) -> PathBuf {
+        let dir = Path::new("target/download").join(name);
+        if dir.exists() {
+            fs::remove_dir_all(&dir).unwrap();
+        }
+        fs::create_dir_all(&dir).unwrap();
+        dir.join("file.tar.gz")
+    }
+
+    #[test]
+    pub fn test_download() {
+        let file = setup("complete");
+        let url = serve(b"0123456789", true);
+        assert_eq!(download(&url, &file).unwrap(), 0);
+        assert_eq!(fs::read(&file).unwrap(), b"0123456789");
+        assert!(!part_file(&file).exists());
+    }
+
+    #[test]
+    pub fn test_download_resume() {
+        let file = setup("resume");
+        fs::write(part_file(&file), b"0123").unwrap();
+        fs::write(part_info_file(&file), "total=10\n").unwrap();
+        let url = serve(b"0123456789", true);
+        assert_eq!(download(&url, &file).unwrap(), 4);
+        assert_eq!(fs::read(&file).unwrap(), b"0123456789");
+        assert!(!part_info_file(&file).exists());
+    }
+
+    #[test]
+    pub fn test_download_resume_changed() {
+        // no validator
+        let file = setup("resume_unknown");
+        fs::write(part_file(&file), b"xxxx").unwrap();
+        let url = serve(b"0123456789", true);
+        assert_eq!(download(&url, &file).unwrap(), 0);
+        assert_eq!(fs::read(&file).unwrap(), b"0123456789");
+
+        // other size (file from another mirror)
+        let file = setup("resume_size");
+        fs::write(part_file(&file), b"xxxx").unwrap();
+        fs::write(part_info_file(&file), "total=20\n").unwrap();
+        let url = serve(b"0123456789", true);
+        assert_eq!(download(&url, &file).unwrap(), 0);
+        assert_eq!(fs::read(&file).unwrap(), b"0123456789");
+
+        // other etag, the server ignores the range
+        let file = setup("resume_etag");
+        fs::write(part_file(&file), b"xxxx").unwrap();
+        fs::write(part_info_file(&file), "total=10\netag=\"v1\"\n").unwrap();
+        let url = serve_etag(b"0123456789", true, Some("\"v2\""));
+        assert_eq!(download(&url, &file).unwrap(), 0);
+        assert_eq!(fs::read(&file).unwrap(), b"0123456789");
+
+        // same etag
+        let file = setup("resume_same_etag");
+        fs::write(part_file(&file), b"0123").unwrap();
+        fs::write(part_info_file(&file), "total=10\netag=\"v2\"\n").unwrap();
+        assert_eq!(download(&url, &file).unwrap(), 4);
+        assert_eq!(fs::read(&file).unwrap(), b"0123456789");
+    }
+
+    #[test]
+    pub fn test_download_resume_server_error() {
+        // the partial file is kept for the next attempt
+        let file = setup("resume_server_error");
+        fs::write(part_file(&file), b"0123").unwrap();
+        fs::write(part_info_file(&file), "total=10\n").unwrap();
+        let error = download(&serve_status("503 Service Unavailable"), &file).unwrap_err();
+        assert!(error.retry);
+        assert_eq!(fs::read(part_file(&file)).unwrap(), b"0123");
+        assert!(part_info_file(&file).exists());
+        let url = serve(b"0123456789", true);
+        assert_eq!(download(&url, &file).unwrap(), 4);
+        assert_eq!(fs::read(&file).unwrap(), b"0123456789");
+
+        // the range can not be served, start again
+        fs::remove_file(&file).unwrap();
+        fs::write(part_file(&file), b"0123").unwrap();
+        fs::write(part_info_file(&file), "total=10\n").unwrap();
+        let url = serve_status("416 Range Not Satisfiable");
+        assert!(!download(&url, &file).unwrap_err().retry);
+        assert!(!part_file(&file).exists());
+    }
+
+    #[test]
+    pub fn test_validator() {
+        let file = setup("validator").with_file_name("file.tar.gz.part.info");
+        let validator = Validator {
+            total: Some(10),
+            etag: Some("W/\"v1\"".to_string()),
+            last_modified: Some("Wed, 01 Jun 2022 12:00:00 GMT".to_string()),
+        };
+        validator.write(&file).unwrap();
+        let read = Validator::read(&file).unwrap();
+        assert_eq!(read, validator);
+        // a weak etag is not used with If-Range
+        assert_eq!(read.if_range(), Some("Wed, 01 Jun 2022 12:00:00 GMT"));
+        assert!(!Validator::default().can_resume());
+    }
+
+    #[test]
+    pub fn test_download_resume_not_supported() {
+        let file = setup("no_range");
+        fs::write(part_file(&file), b"xxxx").unwrap();
+        fs::write(part_info_file(&file), "total=10\n").unwrap();
+        let url = serve(b"0123456789", false);
+        assert_eq!(download(&url, &file).unwrap(), 0);
+        assert_eq!(fs::read(&file).unwrap(), b"0123456789");
+    }
+
+    #[test]
+    pub fn test_progress_line() {
+        let elapsed = Duration::from_secs(4);
+        assert_eq!(
+            progress_line("foo.tar.gz", 4000, 5000, Some(10000), elapsed, false),
+            "Downloading foo.tar.gz: 50% 4.88 KB / 9.77 KB, 1000 B/s, ETA 5s"
+        );
+        assert_eq!(
+            progress_line("foo.tar.gz", 4000, 5000, Some(10000), elapsed, true),
+            "foo.tar.gz [==========          ]  50% 4.88 KB / 9.77 KB 1000 B/s ETA 5s"
+        );
+        assert_eq!(
+            progress_line("foo.tar.gz", 0, 0, None, Duration::ZERO, false),
+            "Downloading foo.tar.gz: 0 B, 0 B/s"
+        );
+        assert_eq!(duration(125), "2m05s");
+        assert_eq!(duration(3720), "1h02m");
+    }
+
+    #[test]
+    pub fn test_display() {
+        let mut display = Display::new();
+        let a = display.add();
+        let b = display.add();
+        display.set(a, "a.tar.gz [====      ]  40%".to_string());
+        let mut out = vec![];
+        display.redraw(&mut out, 80);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "a.tar.gz [====      ]  40%\x1b[K\n"
+        );
+
+        // one line per download
+        display.set(b, "b.tar.gz [==        ]  20%".to_string());
+        let mut out = vec![];
+        display.redraw(&mut out, 80);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "\x1b[1A\ra.tar.gz [====      ]  40%\x1b[K\nb.tar.gz [==        ]  20%\x1b[K\n\x1b[J"
+        );
+
+        // a message goes above the progress lines
+        let mut out = vec![];
+        display.erase(&mut out);
+        display.erase(&mut out);
+        assert_eq!(String::from_utf8(out).unwrap(), "\x1b[2A\r\x1b[J");
+        let mut out = vec![];
+        display.redraw(&mut out, 80);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "a.tar.gz [====      ]  40%\x1b[K\nb.tar.gz [==        ]  20%\x1b[K\n"
+        );
+
+        // nothing is written without progress lines
+        let mut empty = Display::new();
+        let mut out = vec![];
+        empty.redraw(&mut out, 80);
+        empty.erase(&mut out);
+        assert!(out.is_empty());
+
+        // the line of a finished download is cleared, lines are truncated
+        display.remove(a);
+        let mut out = vec![];
+        display.redraw(&mut out, 10);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "\x1b[2A\rb.tar.gz \x1b[K\n\x1b[J"
+        );
+
+        // a redirected stdout gets no escape sequences
+        let file = File::create(setup("display")).unwrap();
+        assert!(!is_display_terminal(file.as_raw_fd()));
+    }
+
+    #[test]
+    pub fn test_download_concurrent() {
+        let files = ["a", "b", "c"].map(|name| setup(&format!("concurrent_{}", name)));
+        let urls = files.clone().map(|_| serve(b"0123456789", true));
+        thread::scope(|scope| {
+            for (url, file) in urls.iter().zip(&files) {
+                scope.spawn(move || download(url, file).unwrap());
+            }
+        });
+        for file in &files {
+            assert_eq!(fs::read(file).unwrap(), b"0123456789");
+        }
+        assert!(DISPLAY.lock().unwrap().lines.is_empty());
+    }
+
+    #[test]
+    pub fn test_source_url() {
+        let base_dir = Path::new("/srv/recipes");
+        let url = |url| source_url(url, base_dir);
+        assert_eq!(
+            url("https://example.com/a.tar.gz").unwrap(),
+            "https://example.com/a.tar.gz"
+        );
+        assert_eq!(
+            url("dist/a.tar.gz").unwrap(),
+            "file:///srv/recipes/dist/a.tar.gz"
+        );
+        assert_eq!(
+            url("file:dist/a.tar.gz").unwrap(),
+            "file:///srv/recipes/dist/a.tar.gz"
+        );
+        assert_eq!(
+            url("/mnt/nfs/a.tar.gz").unwrap(),
+            "file:///mnt/nfs/a.tar.gz"
+        );
+        assert_eq!(
+            url("file:/mnt/nfs/a.tar.gz").unwrap(),
+            "file:///mnt/nfs/a.tar.gz"
+        );
+        assert_eq!(
+            url("file:///mnt/nfs/a.tar.gz").unwrap(),
+            "file:///mnt/nfs/a.tar.gz"
+        );
+        assert_eq!(
+            url("file://localhost/mnt/nfs/a.tar.gz").unwrap(),
+            "file:///mnt/nfs/a.tar.gz"
+        );
+        // dist would be the host
+        assert!(url("file://dist/a.tar.gz").is_err());
+
+        // escapes are decoded from urls, added to paths
+        assert_eq!(
+            url("file:///mnt/my%20nfs/a%2Bb.tar.gz").unwrap(),
+            "file:///mnt/my%20nfs/a+b.tar.gz"
+        );
+        assert_eq!(
+            url("my dist/100%.tar.gz").unwrap(),
+            "file:///srv/recipes/my%20dist/100%25.tar.gz"
+        );
+        assert_eq!(
+            local_path("file:///mnt/my%20nfs/100%25.tar.gz"),
+            Some(PathBuf::from("/mnt/my nfs/100%.tar.gz"))
+        );
+        assert_eq!(
+            local_path("file:///mnt/100%.tar.gz"),
+            Some(PathBuf::from("/mnt/100%.tar.gz"))
+        );
+        assert_eq!(local_path("file://dist/a.tar.gz"), None);
+        assert_eq!(local_path("https://example.com/a.tar.gz"), None);
+    }
+
+    #[test]
+    pub fn test_download_local() {
+        let file = setup("local");
+        let source = file.with_file_name("source.tar.gz");
+        fs::write(&source, b"0123456789").unwrap();
+        let url = format!("file://{}", source.canonicalize().unwrap().display());
+        assert_eq!(download(&url, &file).unwrap(), 0);
+        assert_eq!(fs::read(&file).unwrap(), b"0123456789");
+        assert!(is_same_file(&source, &file));
+        assert!(is_up_to_date(&source, &file));
+
+        // a copy with the size and modification time of the source
+        let copy = file.with_file_name("copy.tar.gz");
+        fs::copy(&source, &copy).unwrap();
+        let modified = fs::metadata(&source).unwrap().modified().unwrap();
+        let set_modified = |time| {
+            File::options()
+                .write(true)
+                .open(&copy)
+                .unwrap()
+                .set_modified(time)
+                .unwrap()
+        };
+        set_modified(modified + Duration::from_secs(1));
+        assert!(!is_up_to_date(&source, &copy));
+        set_modified(modified);
+        assert!(!is_same_file(&source, &copy));
+        assert!(is_up_to_date(&source, &copy));
+        assert!(!is_up_to_date(&source, &file.with_file_name("missing")));
+
+        let missing = format!("file://{}", file.with_file_name("missing").display());
+        assert!(
+            !download(&missing, &file.with_file_name("other"))
+                .unwrap_err()
+                .retry
+        );
+    }
+
+    #[test]
+    pub fn test_download_mirrors() {
+        let file = setup("mirrors");
+        // nothing listens on this port anymore (connection refused)
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let dead_url = format!("http://127.0.0.1:{}/file.tar.gz", port);
+        let url = serve(b"0123456789", true);
+        let urls = vec![dead_url.clone(), url.clone()];
+        assert_eq!(
+            download_mirrors(&urls, &file, 1, Duration::from_millis(10), "").unwrap(),
+            (url, 0)
+        );
+        assert_eq!(fs::read(&file).unwrap(), b"0123456789");
+
+        let file = setup("no_mirror");
+        assert!(download_mirrors(&[dead_url], &file, 0, Duration::ZERO, "").is_err());
+    }
+}
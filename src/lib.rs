@@ -1,14 +1,28 @@
+mod cache;
+mod checksum;
 mod compiler;
 mod core;
 mod dependencies;
+mod download;
 mod installer;
+mod lock;
 mod parser;
+mod receipt;
+mod version;
 
+pub use self::cache::*;
+pub use self::checksum::*;
 pub use self::compiler::*;
 pub use self::core::*;
+pub use self::dependencies::install_plan;
 pub use self::dependencies::resolve as resolve_dependencies;
+pub use self::dependencies::transitive_dependencies;
+pub use self::download::*;
 pub use self::installer::*;
+pub use self::lock::*;
 pub use self::parser::*;
+pub use self::receipt::*;
+pub use self::version::*;
 
 #[cfg(test)]
 pub use self::parser::my_package_def;
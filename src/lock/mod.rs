@@ -0,0 +1,135 @@
+use super::eprint_line;
+use fs2::FileExt;
+use std::fs;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+// directory of the store containing the lock files
+pub const LOCKS_DIR: &str = ".locks";
+
+// Advisory lock on a file (flock)
+// released when dropped (or when the process exits)
+#[derive(Debug)]
+pub struct Lock {
+    pub path: PathBuf,
+    file: File,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LockMode {
+    Shared,
+    Exclusive,
+}
+
+impl Lock {
+    // lock on the whole store
+    // shared by installs, exclusive for removing packages
+    pub fn store(packages_dir: &Path, mode: LockMode, wait: bool) -> Result<Lock, String> {
+        Lock::acquire(
+            &packages_dir.join(LOCKS_DIR).join("store.lock"),
+            mode,
+            wait,
+            "",
+        )
+    }
+
+    // lock on a single package (working directories and package directory)
+    pub fn package(
+        packages_dir: &Path,
+        package_id: &str,
+        wait: bool,
+        prefix: &str,
+    ) -> Result<Lock, String> {
+        Lock::acquire(
+            &packages_dir
+                .join(LOCKS_DIR)
+                .join(format!("{}.lock", package_id)),
+            LockMode::Exclusive,
+            wait,
+            prefix,
+        )
+    }
+
+    // try to get the lock first
+    // if it is held by another process, wait for it or fail
+    // the waiting message is prefixed like the other messages of the package
+    pub fn acquire(path: &Path, mode: LockMode, wait: bool, prefix: &str) -> Result<Lock, String> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)
+                .map_err(|e| format!("Directory {} can not be created: {}", dir.display(), e))?;
+        }
+        let file = File::create(path)
+            .map_err(|e| format!("Lock file {} can not be created: {}", path.display(), e))?;
+        let locked = match mode {
+            LockMode::Shared => FileExt::try_lock_shared(&file),
+            LockMode::Exclusive => FileExt::try_lock_exclusive(&file),
+        };
+        if locked.is_err() {
+            if !wait {
+                return Err(format!(
+                    "Lock {} is held by another store process",
+                    path.display()
+                ));
+            }
+            eprint_line(&format!(
+                "{}Waiting for lock {} held by another store process",
+                prefix,
+                path.display()
+            ));
+            let locked = match mode {
+                LockMode::Shared => FileExt::lock_shared(&file),
+                LockMode::Exclusive => FileExt::lock_exclusive(&file),
+            };
+            if let Err(e) = locked {
+                return Err(format!("Can not lock {}: {}", path.display(), e));
+            }
+        }
+        Ok(Lock {
+            path: path.to_path_buf(),
+            file,
+        })
+    }
+}
+
+impl Lock {
+    // an exclusive lock becomes shared, other processes can then share it
+    // the conversion is not atomic, the lock may be taken in between
+    pub fn share(&self) -> Result<(), String> {
+        FileExt::lock_shared(&self.file)
+            .map_err(|e| format!("Can not lock {}: {}", self.path.display(), e))
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        let _ = FileExt::unlock(&self.file);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_lock() {
+        let packages_dir = Path::new("target/lock");
+        let lock = Lock::package(packages_dir, "mypackage@0.1.0", false, "").unwrap();
+        assert_eq!(
+            Lock::package(packages_dir, "mypackage@0.1.0", false, "")
+                .err()
+                .unwrap(),
+            "Lock target/lock/.locks/mypackage@0.1.0.lock is held by another store process"
+        );
+        drop(lock);
+        let lock = Lock::package(packages_dir, "mypackage@0.1.0", false, "").unwrap();
+        lock.share().unwrap();
+        let path = packages_dir.join(LOCKS_DIR).join("mypackage@0.1.0.lock");
+        assert!(Lock::acquire(&path, LockMode::Shared, false, "").is_ok());
+        assert!(Lock::acquire(&path, LockMode::Exclusive, false, "").is_err());
+        drop(lock);
+
+        let _lock1 = Lock::store(packages_dir, LockMode::Shared, false).unwrap();
+        let _lock2 = Lock::store(packages_dir, LockMode::Shared, false).unwrap();
+        assert!(Lock::store(packages_dir, LockMode::Exclusive, false).is_err());
+    }
+}
@@ -1,3 +1,4 @@
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum ExitCode {
     Success,
     ErrorOptions,
@@ -7,6 +8,12 @@ pub enum ExitCode {
     ErrorPackageNotDefined,
     ErrorInstall,
     ErrorInfo,
+    ErrorChecksum,
+    ErrorVerify,
+    ErrorLock,
+    ErrorLog,
+    ErrorCache,
+    ErrorOffline,
 }
 
 impl ExitCode {
@@ -22,6 +29,12 @@ impl ExitCode {
             ExitCode::ErrorPackageNotDefined => 5,
             ExitCode::ErrorInstall => 8,
             ExitCode::ErrorInfo => 9,
+            ExitCode::ErrorChecksum => 10,
+            ExitCode::ErrorVerify => 11,
+            ExitCode::ErrorLock => 12,
+            ExitCode::ErrorLog => 13,
+            ExitCode::ErrorCache => 14,
+            ExitCode::ErrorOffline => 15,
         };
         std::process::exit(value)
     }
@@ -4,19 +4,28 @@ extern crate store;
 
 mod exit;
 mod options;
+mod output;
 mod pos;
 mod utils;
 
 use exit::*;
 use humansize::FileSize;
 use options::*;
+use output::Output;
 use pos::*;
 use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::fs::File;
 use std::io::Read;
 use std::path::Path;
-use store::{resolve_dependencies, Package, PackageDef};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Mutex};
+use std::thread;
+use store::{
+    build_logs, install_plan, resolve_dependencies, transitive_dependencies, Cache, Checksum,
+    CommandError, FileChange, Installer, InstallerOptions, Lock, LockMode, Package, PackageDef,
+    Receipt, CACHE_DIR, LOGS_DIR, RECEIPTS_DIR,
+};
 use utils::dir_size;
 
 fn main() {
@@ -36,35 +45,37 @@ fn main() {
 
     match options.command.clone() {
         Command::Install(package_queries) => {
-            let packages = find_packages(package_defs, &package_queries);
-            let install_packages = resolve_packages(&packages, &dependencies);
-            for package_def in install_packages {
-                let package = package_def.compile(); // can not fail
-                install(
-                    &package,
-                    &options.packages_dir,
-                    &options.tmp_dir,
-                    options.verbose,
-                );
+            let packages = find_packages(package_defs.clone(), &package_queries);
+            let plan = resolve_packages(&package_defs, &packages);
+            let _lock = lock_store(&options, LockMode::Shared);
+            let result = check_offline_sources(&plan, &dependencies, &options, false)
+                .and_then(|_| download_sources(&plan, &dependencies, &options, false))
+                // the downloaded sources are not pruned until installed
+                .and_then(|_cache_locks| install_packages(plan, &dependencies, &options));
+            match result {
+                Ok(_) => ExitCode::Success.exit(),
+                Err(exit_code) => exit_code.exit(),
             }
-            ExitCode::Success.exit()
         }
         Command::ReInstall(package_queries) => {
             let install_packages = find_packages(package_defs, &package_queries);
+            let _lock = lock_store(&options, LockMode::Exclusive);
+            if let Err(exit_code) = check_offline_sources(&install_packages, &[], &options, true) {
+                exit_code.exit();
+            }
             for package_def in install_packages {
                 let package = package_def.compile(); // can not fail
                 delete_package(&package, &options.packages_dir, options.verbose);
-                install(
-                    &package,
-                    &options.packages_dir,
-                    &options.tmp_dir,
-                    options.verbose,
-                );
+                let output = Output::new(&package.id, false);
+                if let Err(exit_code) = install(&package_def, &dependencies, &options, &output) {
+                    exit_code.exit();
+                }
             }
             ExitCode::Success.exit()
         }
         Command::UnInstall(package_queries) => {
             let install_packages = find_packages(package_defs, &package_queries);
+            let _lock = lock_store(&options, LockMode::Exclusive);
             for package_def in install_packages {
                 let package = package_def.compile(); // can not fail
                 delete_package(&package, &options.packages_dir, options.verbose);
@@ -73,9 +84,56 @@ fn main() {
         }
         Command::Info => info(package_defs, &options.packages_dir),
         Command::Dependencies => display_dependencies(&dependencies),
+        Command::Verify(package_queries) => verify(&package_queries, &options.packages_dir),
+        Command::Log(package_query) => {
+            let package_defs = find_packages(package_defs, &vec![package_query]);
+            show_log(&package_defs[0], &options.tmp_dir)
+        }
+        Command::BuildShell(package_query) => {
+            let package_defs = find_packages(package_defs, &vec![package_query]);
+            let _lock = lock_store(&options, LockMode::Shared);
+            match build_shell(&package_defs[0], &dependencies, &options) {
+                Ok(_) => ExitCode::Success.exit(),
+                Err(exit_code) => exit_code.exit(),
+            }
+        }
+        Command::Fetch(package_queries) => {
+            let packages = find_packages(package_defs.clone(), &package_queries);
+            let plan = resolve_packages(&package_defs, &packages);
+            let _lock = lock_store(&options, LockMode::Shared);
+            match download_sources(&plan, &dependencies, &options, true) {
+                Ok(_) => ExitCode::Success.exit(),
+                Err(exit_code) => exit_code.exit(),
+            }
+        }
+        Command::CacheList => cache_list(&cache(&options)),
+        Command::CacheClean => {
+            let _lock = lock_store(&options, LockMode::Exclusive);
+            cache_clean(&cache(&options))
+        }
     }
 }
 
+// the lock is released when dropped
+fn lock_store(options: &Options, mode: LockMode) -> Lock {
+    match Lock::store(&options.packages_dir, mode, !options.no_wait) {
+        Ok(lock) => lock,
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::ErrorLock.exit()
+        }
+    }
+}
+
+// packages are installed in non-hidden directories of the store
+fn is_package_dir(path: &Path) -> bool {
+    let hidden = match path.file_name() {
+        None => true,
+        Some(name) => name.to_string_lossy().starts_with('.'),
+    };
+    path.is_dir() && !hidden
+}
+
 fn parse_database_file(db_file: &Path) -> Vec<PackageDef> {
     let mut file = File::open(db_file).expect("file exists");
     let mut content = String::new();
@@ -85,7 +143,7 @@ fn parse_database_file(db_file: &Path) -> Vec<PackageDef> {
     } else {
         let mut db_parser = store::Parser::init(&content);
         match db_parser.packages() {
-            Ok(packages) => packages,
+            Ok(packages) => resolve_local_sources(packages, db_file),
             Err(e) => {
                 let pos = Pos::find(&content, e.offset);
                 eprintln!(
@@ -101,6 +159,31 @@ fn parse_database_file(db_file: &Path) -> Vec<PackageDef> {
     }
 }
 
+// local sources are relative to the directory of the database file
+fn resolve_local_sources(mut package_defs: Vec<PackageDef>, db_file: &Path) -> Vec<PackageDef> {
+    let db_dir = match db_file.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let db_dir = db_dir
+        .canonicalize()
+        .unwrap_or_else(|_| db_dir.to_path_buf());
+    for package_def in &mut package_defs {
+        let id = package_def.id();
+        let urls = package_def.url.iter_mut().chain(&mut package_def.mirrors);
+        for url in urls {
+            match store::source_url(url, &db_dir) {
+                Ok(source_url) => *url = source_url,
+                Err(e) => {
+                    eprintln!("Package {}: {}", id, e);
+                    ExitCode::ErrorParse.exit()
+                }
+            }
+        }
+    }
+    package_defs
+}
+
 fn resolve_deps(package_defs: &Vec<PackageDef>) -> Vec<(String, PackageDef)> {
     match resolve_dependencies(package_defs) {
         Ok(deps) => deps,
@@ -137,23 +220,15 @@ fn find_packages(package_defs: Vec<PackageDef>, package_queries: &Vec<String>) -
 }
 
 // add dependent packages to install
-fn resolve_packages(
-    package_defs: &Vec<PackageDef>,
-    dependencies: &Vec<(String, PackageDef)>,
-) -> Vec<PackageDef> {
-    let mut install_packages = vec![];
-
-    for package in package_defs {
-        for dep in dependencies {
-            if dep.0 == package.id() && !install_packages.contains(&dep.1) {
-                install_packages.push(dep.1.clone());
-            }
-        }
-        if !install_packages.contains(package) {
-            install_packages.push(package.clone());
+// dependencies are installed first
+fn resolve_packages(package_defs: &[PackageDef], packages: &[PackageDef]) -> Vec<PackageDef> {
+    match install_plan(package_defs, packages) {
+        Ok(packages) => packages,
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::ErrorDependencies.exit()
         }
     }
-    install_packages
 }
 //
 // fn compile(package_def: &PackageDef) -> Package {
@@ -167,6 +242,15 @@ fn resolve_packages(
 // }
 
 fn delete_package(package: &Package, packages_dir: &Path, _verbose: bool) {
+    let receipt_file = packages_dir
+        .join(RECEIPTS_DIR)
+        .join(format!("{}.json", package.id));
+    if receipt_file.exists() {
+        if let Err(e) = fs::remove_file(&receipt_file) {
+            eprintln!("error deleting {} - {}", receipt_file.display(), e);
+            ExitCode::ErrorInstall.exit();
+        }
+    }
     let package_dir = packages_dir.join(package.id.clone());
     if package_dir.exists() {
         match fs::remove_dir_all(package_dir.display().to_string()) {
@@ -181,54 +265,465 @@ fn delete_package(package: &Package, packages_dir: &Path, _verbose: bool) {
     }
 }
 
-fn install(package: &Package, packages_dir: &Path, tmp_dir: &Path, verbose: bool) {
-    if verbose {
-        eprintln!("Installing {}", package.id);
+// download the sources of the packages of the plan before building any of them,
+// up to options.downloads at a time
+// the network is not idle during long builds, the install of each package
+// then finds its source already downloaded
+// the messages are displayed in the order of the plan once all downloads are done
+// after a failure, no download is started and the running ones are awaited
+// with fetch (store fetch), the sources of installed packages are also downloaded
+// and verified
+// return the shared locks of the cache entries of the downloaded sources
+fn download_sources(
+    packages: &[PackageDef],
+    dependencies: &[(String, PackageDef)],
+    options: &Options,
+    fetch: bool,
+) -> Result<Vec<Lock>, ExitCode> {
+    let packages = packages
+        .iter()
+        .filter(|package_def| package_def.url.is_some())
+        .collect::<Vec<&PackageDef>>();
+    let parallel = packages.len() > 1;
+    let queue = Mutex::new(packages.iter().enumerate());
+    let results = Mutex::new((0..packages.len()).map(|_| None).collect::<Vec<_>>());
+    let failed = AtomicBool::new(false);
+    thread::scope(|scope| {
+        for _ in 0..options.downloads {
+            scope.spawn(|| loop {
+                if failed.load(Ordering::SeqCst) {
+                    break;
+                }
+                let (index, package_def) = match queue.lock().unwrap().next() {
+                    Some(next) => next,
+                    None => break,
+                };
+                let output = Output::new(&package_def.id(), parallel);
+                let result = download_source(package_def, dependencies, options, fetch, &output);
+                if result.is_err() {
+                    failed.store(true, Ordering::SeqCst);
+                }
+                results.lock().unwrap()[index] = Some(result);
+            });
+        }
+    });
+    let mut exit_code = None;
+    let mut cache_locks = vec![];
+    for (package_def, result) in packages.iter().zip(results.into_inner().unwrap()) {
+        let output = Output::new(&package_def.id(), parallel);
+        match result {
+            Some(Ok((messages, cache_lock))) => {
+                for message in messages {
+                    output.println(&message);
+                }
+                cache_locks.extend(cache_lock);
+            }
+            Some(Err(code)) => {
+                exit_code.get_or_insert(code);
+            }
+            None => {}
+        }
     }
-    let mut package_installer = match store::Installer::init(packages_dir, tmp_dir, package) {
-        Ok(inst) => inst,
-        Err(e) => {
-            eprintln!("{}", e);
-            ExitCode::ErrorInstall.exit();
+    match exit_code {
+        None => Ok(cache_locks),
+        Some(code) => Err(code),
+    }
+}
+
+// return the messages of the downloads and the lock of the cache entry,
+// errors are displayed right away
+fn download_source(
+    package_def: &PackageDef,
+    dependencies: &[(String, PackageDef)],
+    options: &Options,
+    fetch: bool,
+    output: &Output,
+) -> Result<(Vec<String>, Option<Lock>), ExitCode> {
+    let package = &package_def.compile(); // can not fail
+    let _lock = lock_package(&package.id, options, output)?;
+    let mut package_installer = init_installer(package, dependencies, options, output)?;
+    if !fetch && package_installer.is_installed() {
+        return Ok((vec![], None));
+    }
+    let mut messages = vec![];
+    for command in &package.commands {
+        let download = match command {
+            store::Command::Download(_) => true,
+            store::Command::Verify(_) => fetch,
+            _ => false,
+        };
+        if !download {
+            continue;
         }
-    };
+        match package_installer.exec_command(command, options.verbose) {
+            Ok(message) => messages.push(message),
+            Err(CommandError::Failed(e)) => {
+                output.eprintln(&e);
+                return Err(ExitCode::ErrorInstall);
+            }
+            Err(CommandError::ChecksumMismatch {
+                file,
+                expected,
+                actual,
+            }) => {
+                checksum_mismatch(output, &file, &expected, &actual);
+                return Err(ExitCode::ErrorChecksum);
+            }
+        }
+    }
+    Ok((messages, package_installer.take_cache_lock()))
+}
+
+// in offline mode, fail before installing anything if a source must be downloaded
+// the sources of installed packages are checked when they are reinstalled
+fn check_offline_sources(
+    packages: &[PackageDef],
+    dependencies: &[(String, PackageDef)],
+    options: &Options,
+    reinstall: bool,
+) -> Result<(), ExitCode> {
+    if !options.offline {
+        return Ok(());
+    }
+    let mut missing = vec![];
+    for package_def in packages {
+        let package = &package_def.compile(); // can not fail
+        let output = Output::new(&package.id, false);
+        let package_installer = init_installer(package, dependencies, options, &output)?;
+        if !reinstall && package_installer.is_installed() {
+            continue;
+        }
+        for command in &package.commands {
+            if let store::Command::Download(urls) = command {
+                if !package_installer.is_downloaded(urls) {
+                    missing.push(format!("  {}: {}", package.id, urls[0]));
+                }
+            }
+        }
+    }
+    if missing.is_empty() {
+        return Ok(());
+    }
+    eprintln!("Sources missing from the download cache (offline mode):");
+    for line in missing {
+        eprintln!("{}", line);
+    }
+    Err(ExitCode::ErrorOffline)
+}
+
+// install the packages of the plan, up to options.jobs at a time
+// a package is started once all its dependencies are installed
+// after a failure, no package is started and the running ones are awaited
+fn install_packages(
+    packages: Vec<PackageDef>,
+    dependencies: &[(String, PackageDef)],
+    options: &Options,
+) -> Result<(), ExitCode> {
+    if options.jobs <= 1 {
+        for package_def in packages {
+            let output = Output::new(&package_def.id(), false);
+            install(&package_def, dependencies, options, &output)?;
+        }
+        return Ok(());
+    }
+    let mut pending = packages;
+    let mut installed: HashSet<String> = HashSet::new();
+    let mut running = 0;
+    let mut result = Ok(());
+    thread::scope(|scope| {
+        let (sender, receiver) = mpsc::channel();
+        loop {
+            while result.is_ok() && running < options.jobs {
+                let package_def = match next_ready(&pending, dependencies, &installed) {
+                    Some(index) => pending.remove(index),
+                    None => break,
+                };
+                let sender = sender.clone();
+                scope.spawn(move || {
+                    let output = Output::new(&package_def.id(), true);
+                    let result = install(&package_def, dependencies, options, &output);
+                    sender.send((package_def.id(), result)).unwrap();
+                });
+                running += 1;
+            }
+            if running == 0 {
+                break;
+            }
+            let (package_id, package_result) = receiver.recv().unwrap();
+            running -= 1;
+            match package_result {
+                Ok(_) => {
+                    installed.insert(package_id);
+                }
+                Err(exit_code) => {
+                    if result.is_ok() {
+                        result = Err(exit_code);
+                    }
+                }
+            }
+        }
+    });
+    result
+}
+
+// index of the first pending package whose dependencies are all installed
+fn next_ready(
+    pending: &[PackageDef],
+    dependencies: &[(String, PackageDef)],
+    installed: &HashSet<String>,
+) -> Option<usize> {
+    pending.iter().position(|package_def| {
+        dependencies
+            .iter()
+            .filter(|(id, _)| *id == package_def.id())
+            .all(|(_, dependency)| installed.contains(&dependency.id()))
+    })
+}
+
+fn install(
+    package_def: &PackageDef,
+    dependencies: &[(String, PackageDef)],
+    options: &Options,
+    output: &Output,
+) -> Result<(), ExitCode> {
+    let verbose = options.verbose;
+    let package = &package_def.compile(); // can not fail
+    if verbose {
+        output.eprintln(&format!("Installing {}", package.id));
+    }
+    let _lock = lock_package(&package.id, options, output)?;
+    let mut package_installer = init_installer(package, dependencies, options, output)?;
 
     if package_installer.is_installed() {
-        println!("Package {} already installed", package.id);
-        return;
+        output.println(&format!("Package {} already installed", package.id));
+        return Ok(());
     }
 
     match package_installer.create_directory() {
-        Ok(message) => println!("{}", message),
+        Ok(message) => output.println(&message),
         Err(e) => {
-            eprintln!("{}", e);
-            ExitCode::ErrorInstall.exit();
+            output.eprintln(&e);
+            return Err(ExitCode::ErrorInstall);
         }
     }
-    if let Err(e) = package_installer.create_directory() {
-        eprintln!("{}", e);
-        ExitCode::ErrorInstall.exit();
+    exec_commands(&mut package_installer, &package.commands, options, output)?;
+    let package_dependencies = dependencies
+        .iter()
+        .filter(|dep| dep.0 == package.id)
+        .map(|dep| dep.1.id())
+        .collect();
+    match package_installer.commit() {
+        Ok(message) => output.println(&message),
+        Err(e) => {
+            output.eprintln(&e);
+            return Err(install_failed(
+                &package_installer,
+                options,
+                output,
+                ExitCode::ErrorInstall,
+            ));
+        }
     }
+    // written last, the package is complete
+    match package_installer.write_receipt(package_def, package_dependencies) {
+        Ok(message) => {
+            if verbose {
+                output.eprintln(&message);
+            }
+        }
+        Err(e) => {
+            output.eprintln(&e);
+            match package_installer.rollback() {
+                Ok(message) => output.println(&message),
+                Err(e) => output.eprintln(&e),
+            }
+            return Err(install_failed(
+                &package_installer,
+                options,
+                output,
+                ExitCode::ErrorInstall,
+            ));
+        }
+    }
+    output.println(&format!("Package {} successfully installed", package.id));
+    Ok(())
+}
 
-    for command in &package.commands {
-        if verbose {
-            eprintln!("Executing {:?}", command);
+// the lock is released when dropped
+fn lock_package(package_id: &str, options: &Options, output: &Output) -> Result<Lock, ExitCode> {
+    Lock::package(
+        &options.packages_dir,
+        package_id,
+        !options.no_wait,
+        &output.prefix,
+    )
+    .map_err(|e| {
+        output.eprintln(&e);
+        ExitCode::ErrorLock
+    })
+}
+
+fn init_installer(
+    package: &Package,
+    dependencies: &[(String, PackageDef)],
+    options: &Options,
+    output: &Output,
+) -> Result<Installer, ExitCode> {
+    let mut installer_options = InstallerOptions {
+        retries: options.retries,
+        build_timeout: options.build_timeout,
+        cache_dir: options.cache_dir.clone(),
+        cache_max_size: options.cache_max_size,
+        offline: options.offline,
+        ..InstallerOptions::default()
+    };
+    installer_options
+        .keep_env
+        .extend(options.keep_env.iter().cloned());
+    let mut package_installer = match Installer::init(
+        &options.packages_dir,
+        &options.tmp_dir,
+        package,
+        &installer_options,
+    ) {
+        Ok(inst) => inst,
+        Err(e) => {
+            output.eprintln(&e);
+            return Err(ExitCode::ErrorInstall);
         }
-        match package_installer.exec_command(command, verbose) {
+    };
+    package_installer.dependencies = transitive_dependencies(&package.id, dependencies);
+    package_installer.output_prefix = output.prefix.clone();
+    Ok(package_installer)
+}
+
+fn exec_commands(
+    package_installer: &mut Installer,
+    commands: &[store::Command],
+    options: &Options,
+    output: &Output,
+) -> Result<(), ExitCode> {
+    for command in commands {
+        if options.verbose {
+            output.eprintln(&format!("Executing {:?}", command));
+        }
+        match package_installer.exec_command(command, options.verbose) {
             Ok(message) => {
                 if !message.is_empty() {
-                    println!("{}", message);
+                    output.println(&message);
                 }
             }
-            Err(e) => {
-                eprintln!("{}", e);
-                let message = package_installer.delete_directory();
-                println!("{}", message);
-                ExitCode::ErrorInstall.exit();
+            Err(CommandError::Failed(e)) => {
+                output.eprintln(&e);
+                return Err(install_failed(
+                    package_installer,
+                    options,
+                    output,
+                    ExitCode::ErrorInstall,
+                ));
             }
+            Err(CommandError::ChecksumMismatch {
+                file,
+                expected,
+                actual,
+            }) => {
+                checksum_mismatch(output, &file, &expected, &actual);
+                return Err(install_failed(
+                    package_installer,
+                    options,
+                    output,
+                    ExitCode::ErrorChecksum,
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn checksum_mismatch(output: &Output, file: &Path, expected: &Checksum, actual: &Checksum) {
+    output.eprintln(&format!("Checksum mismatch for {}", file.display()));
+    output.eprintln(&format!(
+        "  expected {}: {}",
+        expected.algorithm.name(),
+        expected.value
+    ));
+    output.eprintln(&format!(
+        "  actual {}: {}",
+        actual.algorithm.name(),
+        actual.value
+    ));
+}
+
+// the receipt (if already written) and the staging directory are deleted
+// unless --keep-failed is set, the working directories are then kept for debugging
+fn install_failed(
+    package_installer: &Installer,
+    options: &Options,
+    output: &Output,
+    exit_code: ExitCode,
+) -> ExitCode {
+    if let Some(message) = package_installer.remove_receipt() {
+        output.println(&message);
+    }
+    if options.keep_failed {
+        output.println(&format!(
+            "Directory {} has been kept",
+            package_installer.staging_dir.display()
+        ));
+        output.println(&format!(
+            "Directory {} has been kept",
+            package_installer.extract_dir.display()
+        ));
+    } else {
+        let message = package_installer.delete_directory();
+        output.println(&message);
+    }
+    exit_code
+}
+
+// prepare the build of the package (commands before the build script)
+// and run an interactive shell with the environment of the build script
+// the package is prepared in its staging directory, an installed package is never modified
+fn build_shell(
+    package_def: &PackageDef,
+    dependencies: &[(String, PackageDef)],
+    options: &Options,
+) -> Result<(), ExitCode> {
+    let package = &package_def.compile(); // can not fail
+    let output = Output::new(&package.id, false);
+    let _lock = lock_package(&package.id, options, &output)?;
+    let mut package_installer = init_installer(package, dependencies, options, &output)?;
+    match package_installer.create_directory() {
+        Ok(message) => println!("{}", message),
+        Err(e) => {
+            eprintln!("{}", e);
+            return Err(ExitCode::ErrorInstall);
+        }
+    }
+    let commands = package
+        .commands
+        .iter()
+        .take_while(|c| !matches!(c, store::Command::Shell(_) | store::Command::Copy()))
+        .cloned()
+        .collect::<Vec<store::Command>>();
+    exec_commands(&mut package_installer, &commands, options, &output)?;
+    println!(
+        "Entering build shell in {} (exit to leave)",
+        package_installer.state.current_dir.display()
+    );
+    match package_installer.interactive_shell() {
+        Ok(_) => {
+            println!(
+                "Directory {} has been kept",
+                package_installer.staging_dir.display()
+            );
+            Ok(())
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            Err(ExitCode::ErrorInstall)
         }
     }
-    println!("Package {} successfully installed", package.id);
 }
 
 // display size of the installed package or - (if not installed)
@@ -249,7 +744,7 @@ fn info(package_defs: Vec<PackageDef>, packages_dir: &Path) {
     };
     for dir_entry in dir_entries {
         let path = dir_entry.unwrap().path();
-        if !path.is_dir() {
+        if !is_package_dir(&path) {
             continue;
         }
         let package_name = path.clone();
@@ -295,7 +790,7 @@ fn info(package_defs: Vec<PackageDef>, packages_dir: &Path) {
     let mut name_column_length = 0;
     for package in packages.clone() {
         let tokens = package.split('@').collect::<Vec<&str>>();
-        let name = tokens.get(0).unwrap();
+        let name = tokens.first().unwrap();
         if name.len() > name_column_length {
             name_column_length = name.len();
         }
@@ -322,7 +817,7 @@ fn info(package_defs: Vec<PackageDef>, packages_dir: &Path) {
             "obsolete".to_string()
         };
         let tokens = package.split('@').collect::<Vec<&str>>();
-        let name = tokens.get(0).unwrap();
+        let name = tokens.first().unwrap();
         let version = match tokens.get(1) {
             None => "",
             Some(v) => v,
@@ -344,3 +839,207 @@ fn display_dependencies(dependencies: &Vec<(String, PackageDef)>) {
         println!("{} -> {}", dep.0, dep.1.id())
     }
 }
+
+// download cache from the options, default is <store>/.cache
+fn cache(options: &Options) -> Cache {
+    Cache {
+        dir: match &options.cache_dir {
+            Some(dir) => dir.clone(),
+            None => options.packages_dir.join(CACHE_DIR),
+        },
+        max_size: options.cache_max_size,
+    }
+}
+
+// key, file name, size and url of each cached download
+fn cache_list(cache: &Cache) -> ! {
+    let entries = cache.entries();
+    let format_size = |size: u64| {
+        size.file_size(humansize::file_size_opts::CONVENTIONAL)
+            .unwrap()
+    };
+    for entry in &entries {
+        println!(
+            "{} {} {} {}",
+            entry.key,
+            entry.file.file_name().unwrap().to_string_lossy(),
+            format_size(entry.size),
+            entry.url.as_deref().unwrap_or("-")
+        );
+    }
+    let total = entries.iter().map(|e| e.size).sum::<u64>();
+    println!(
+        "{} cached files, {} in {}",
+        entries.len(),
+        format_size(total),
+        cache.dir.display()
+    );
+    ExitCode::Success.exit()
+}
+
+fn cache_clean(cache: &Cache) -> ! {
+    match cache.clean() {
+        Ok(count) => {
+            println!(
+                "{} cache entries removed from {}",
+                count,
+                cache.dir.display()
+            );
+            ExitCode::Success.exit()
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::ErrorCache.exit()
+        }
+    }
+}
+
+// display the last build log of the package
+fn show_log(package_def: &PackageDef, tmp_dir: &Path) {
+    let logs_dir = tmp_dir.join(package_def.id()).join(LOGS_DIR);
+    match build_logs(&logs_dir).last() {
+        None => {
+            eprintln!("No build log for package {}", package_def.id());
+            ExitCode::ErrorLog.exit()
+        }
+        Some(log_file) => match fs::read_to_string(log_file) {
+            Ok(content) => {
+                print!("{}", content);
+                ExitCode::Success.exit()
+            }
+            Err(e) => {
+                eprintln!("Can not read {}: {}", log_file.display(), e);
+                ExitCode::ErrorLog.exit()
+            }
+        },
+    }
+}
+
+// compare installed packages with their receipt
+// all the installed packages are verified if no query is given
+fn verify(package_queries: &[String], packages_dir: &Path) {
+    let mut package_ids = vec![];
+    if let Ok(dir_entries) = fs::read_dir(packages_dir) {
+        for dir_entry in dir_entries {
+            let path = dir_entry.unwrap().path();
+            if !is_package_dir(&path) {
+                continue;
+            }
+            package_ids.push(path.file_name().unwrap().to_str().unwrap().to_string());
+        }
+    }
+    package_ids.sort();
+
+    let matches = |package_query: &str, package_id: &str| {
+        package_query == package_id || package_id.split('@').next() == Some(package_query)
+    };
+    for package_query in package_queries {
+        if !package_ids.iter().any(|id| matches(package_query, id)) {
+            eprintln!("Package {} is not installed", package_query);
+            ExitCode::ErrorPackageNotDefined.exit()
+        }
+    }
+
+    let mut drift = false;
+    for package_id in package_ids {
+        if !package_queries.is_empty() && !package_queries.iter().any(|q| matches(q, &package_id)) {
+            continue;
+        }
+        let receipt_file = packages_dir
+            .join(RECEIPTS_DIR)
+            .join(format!("{}.json", package_id));
+        // installed before receipts were introduced
+        if !receipt_file.exists() {
+            println!(
+                "Package {} has no receipt, it can not be verified",
+                package_id
+            );
+            continue;
+        }
+        let receipt = match Receipt::read(&receipt_file) {
+            Ok(receipt) => receipt,
+            Err(e) => {
+                println!("Package {} can not be verified", package_id);
+                eprintln!("  {}", e);
+                drift = true;
+                continue;
+            }
+        };
+        let changes = match receipt.changes(&packages_dir.join(&package_id)) {
+            Ok(changes) => changes,
+            Err(e) => {
+                eprintln!("Can not read {}: {}", package_id, e);
+                ExitCode::ErrorVerify.exit()
+            }
+        };
+        if changes.is_empty() {
+            println!("Package {} OK", package_id);
+        } else {
+            drift = true;
+            println!("Package {} has been modified", package_id);
+            for change in changes {
+                match change {
+                    FileChange::Added(path) => println!("  added {}", path),
+                    FileChange::Removed(path) => println!("  removed {}", path),
+                    FileChange::Modified(path) => println!("  modified {}", path),
+                }
+            }
+        }
+    }
+    if drift {
+        ExitCode::ErrorVerify.exit()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn package_def(name: &str, depends: &[&str]) -> PackageDef {
+        PackageDef {
+            name: name.to_string(),
+            version: "0.1.0".to_string(),
+            depends: depends.iter().map(|s| s.to_string()).collect(),
+            url: None,
+            mirrors: vec![],
+            checksum: None,
+            archive: None,
+            strip_components: None,
+            subdir: None,
+            env: vec![],
+            timeout: None,
+            build: Some("true".to_string()),
+        }
+    }
+
+    #[test]
+    pub fn test_next_ready() {
+        // a depends on b and c, which both depend on d
+        let package_defs = vec![
+            package_def("a", &["b", "c"]),
+            package_def("b", &["d"]),
+            package_def("c", &["d"]),
+            package_def("d", &[]),
+        ];
+        let dependencies = resolve_dependencies(&package_defs).unwrap();
+        let mut pending = install_plan(&package_defs, &package_defs[..1]).unwrap();
+        let mut installed = HashSet::new();
+        let start = |pending: &mut Vec<PackageDef>, installed: &HashSet<String>| {
+            next_ready(pending, &dependencies, installed).map(|index| pending.remove(index).name)
+        };
+
+        assert_eq!(start(&mut pending, &installed), Some("d".to_string()));
+        // nothing else until d is installed
+        assert_eq!(start(&mut pending, &installed), None);
+        installed.insert("d@0.1.0".to_string());
+        // b and c run in parallel
+        assert_eq!(start(&mut pending, &installed), Some("b".to_string()));
+        assert_eq!(start(&mut pending, &installed), Some("c".to_string()));
+        installed.insert("c@0.1.0".to_string());
+        // a waits for b
+        assert_eq!(start(&mut pending, &installed), None);
+        installed.insert("b@0.1.0".to_string());
+        assert_eq!(start(&mut pending, &installed), Some("a".to_string()));
+        assert!(pending.is_empty());
+    }
+}
@@ -1,4 +1,5 @@
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Options {
@@ -7,6 +8,16 @@ pub struct Options {
     pub packages_dir: PathBuf,
     pub tmp_dir: PathBuf,
     pub verbose: bool,
+    pub no_wait: bool,
+    pub retries: u32,
+    pub keep_env: Vec<String>,
+    pub keep_failed: bool,
+    pub build_timeout: Option<Duration>,
+    pub jobs: usize,
+    pub downloads: usize,
+    pub cache_dir: Option<PathBuf>,
+    pub cache_max_size: Option<u64>,
+    pub offline: bool,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -16,6 +27,12 @@ pub enum Command {
     UnInstall(Vec<String>),
     Info,
     Dependencies,
+    Verify(Vec<String>),
+    Log(String),
+    BuildShell(String),
+    Fetch(Vec<String>),
+    CacheList,
+    CacheClean,
 }
 
 // clap (unfortunately) panics when options are not good
@@ -49,12 +66,71 @@ pub fn parse_options() -> Result<Options, String> {
                 .long("verbose")
                 .help("Turn verbose mode"),
         )
+        .arg(
+            clap::Arg::new("no_wait")
+                .long("no-wait")
+                .help("Fail instead of waiting when the store is locked by another process"),
+        )
+        .arg(
+            clap::Arg::new("retries")
+                .long("retries")
+                .takes_value(true)
+                .help("Number of download retries for each url (default is 3)"),
+        )
+        .arg(
+            clap::Arg::new("keep_env")
+                .long("keep-env")
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .help("Pass an environment variable to build scripts (in addition to PATH, HOME, LANG, LC_ALL, TERM and TZ)"),
+        )
+        .arg(
+            clap::Arg::new("build_timeout")
+                .long("build-timeout")
+                .takes_value(true)
+                .help("Maximum duration of build scripts: 90, 90s, 30m or 2h (unless defined by the package)"),
+        )
+        .arg(
+            clap::Arg::new("jobs")
+                .long("jobs")
+                .short('j')
+                .takes_value(true)
+                .help("Number of packages installed in parallel (default is 1)"),
+        )
+        .arg(
+            clap::Arg::new("downloads")
+                .long("downloads")
+                .takes_value(true)
+                .help("Number of sources downloaded in parallel (default is 4)"),
+        )
+        .arg(
+            clap::Arg::new("cache_dir")
+                .long("cache-dir")
+                .takes_value(true)
+                .help("Specify download cache directory (default is <packages-dir>/.cache)"),
+        )
+        .arg(
+            clap::Arg::new("cache_max_size")
+                .long("cache-max-size")
+                .takes_value(true)
+                .help("Maximum size of the download cache: 500M, 10G... (default is unlimited)"),
+        )
+        .arg(
+            clap::Arg::new("offline")
+                .long("offline")
+                .help("Never download, install only from the download cache"),
+        )
+        .arg(
+            clap::Arg::new("keep_failed")
+                .long("keep-failed")
+                .help("Keep the staging and extract directories of a failed install"),
+        )
         .subcommand(
             clap::Command::new("install")
                 .about("Install a specific package")
                 .arg(
                     clap::Arg::new("package_queries")
-                        .help("Package to be installed: NAME or NAME:VERSION")
+                        .help("Package to be installed: NAME or NAME@VERSION")
                         .multiple_occurrences(true)
                         .required(true),
                 ),
@@ -64,7 +140,7 @@ pub fn parse_options() -> Result<Options, String> {
                 .about("Re-Install a specific package")
                 .arg(
                     clap::Arg::new("package_queries")
-                        .help("Package to be reinstalled: NAME or NAME:VERSION")
+                        .help("Package to be reinstalled: NAME or NAME@VERSION")
                         .multiple_occurrences(true)
                         .required(true),
                 ),
@@ -74,13 +150,57 @@ pub fn parse_options() -> Result<Options, String> {
                 .about("Uninstall a specific package")
                 .arg(
                     clap::Arg::new("package_queries")
-                        .help("Package to be uninstall: NAME or NAME:VERSION")
+                        .help("Package to be uninstall: NAME or NAME@VERSION")
                         .multiple_occurrences(true)
                         .required(true),
                 ),
         )
         .subcommand(clap::Command::new("info").about("List packages (installed and/or defined)"))
-        .subcommand(clap::Command::new("dependencies").about("List dependencies tree"));
+        .subcommand(clap::Command::new("dependencies").about("List dependencies tree"))
+        .subcommand(
+            clap::Command::new("verify")
+                .about("Check installed packages against their install receipt")
+                .arg(
+                    clap::Arg::new("package_queries")
+                        .help("Package to be verified: NAME or NAME@VERSION (default all)")
+                        .multiple_occurrences(true),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("log")
+                .about("Show the last build log of a package")
+                .arg(
+                    clap::Arg::new("package_query")
+                        .help("Package: NAME or NAME@VERSION")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("build-shell")
+                .about("Prepare the build of a package and open a shell with the build environment")
+                .arg(
+                    clap::Arg::new("package_query")
+                        .help("Package: NAME or NAME@VERSION")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("fetch")
+                .about("Download the sources of packages (and their dependencies) into the download cache")
+                .arg(
+                    clap::Arg::new("package_queries")
+                        .help("Package to be fetched: NAME or NAME@VERSION")
+                        .multiple_occurrences(true)
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            clap::Command::new("cache")
+                .about("Manage the download cache")
+                .subcommand_required(true)
+                .subcommand(clap::Command::new("list").about("List cached downloads"))
+                .subcommand(clap::Command::new("clean").about("Remove all cached downloads")),
+        );
     let matches = command.clone().get_matches();
 
     let command = if let Some(("install", install_options)) = matches.subcommand() {
@@ -102,6 +222,32 @@ pub fn parse_options() -> Result<Options, String> {
         Command::Info
     } else if let Some(("dependencies", _)) = matches.subcommand() {
         Command::Dependencies
+    } else if let Some(("verify", verify_options)) = matches.subcommand() {
+        let package_queries = match verify_options.values_of("package_queries") {
+            None => vec![],
+            Some(values) => values.map(|s| s.to_string()).collect(),
+        };
+        Command::Verify(package_queries)
+    } else if let Some(("log", log_options)) = matches.subcommand() {
+        let package_query = log_options
+            .value_of("package_query")
+            .expect("package_query");
+        Command::Log(package_query.to_string())
+    } else if let Some(("build-shell", shell_options)) = matches.subcommand() {
+        let package_query = shell_options
+            .value_of("package_query")
+            .expect("package_query");
+        Command::BuildShell(package_query.to_string())
+    } else if let Some(("fetch", fetch_options)) = matches.subcommand() {
+        let package_queries = fetch_options
+            .values_of("package_queries")
+            .expect("package_queries");
+        Command::Fetch(package_queries.map(|s| s.to_string()).collect())
+    } else if let Some(("cache", cache_options)) = matches.subcommand() {
+        match cache_options.subcommand() {
+            Some(("clean", _)) => Command::CacheClean,
+            _ => Command::CacheList,
+        }
     } else {
         command.clone().print_help().unwrap();
         std::process::exit(2);
@@ -111,15 +257,95 @@ pub fn parse_options() -> Result<Options, String> {
     let tmp_dir = get_tmp_dir(matches.value_of("tmp_dir"))?;
     let packages_dir = get_packages_dir(matches.value_of("packages_dir"))?;
     let verbose = matches.is_present("verbose");
+    let no_wait = matches.is_present("no_wait");
+    let retries = get_retries(matches.value_of("retries"))?;
+    let keep_failed = matches.is_present("keep_failed");
+    let build_timeout = get_build_timeout(matches.value_of("build_timeout"))?;
+    let jobs = get_jobs(matches.value_of("jobs"))?;
+    let downloads = get_downloads(matches.value_of("downloads"))?;
+    let cache_dir = get_cache_dir(matches.value_of("cache_dir"));
+    let offline = matches.is_present("offline");
+    let cache_max_size = get_cache_max_size(matches.value_of("cache_max_size"))?;
+    let keep_env = match matches.values_of("keep_env") {
+        None => vec![],
+        Some(values) => values.map(|s| s.to_string()).collect(),
+    };
     Ok(Options {
         command,
         db_file,
         tmp_dir,
         packages_dir,
         verbose,
+        no_wait,
+        retries,
+        keep_env,
+        keep_failed,
+        build_timeout,
+        jobs,
+        downloads,
+        cache_dir,
+        cache_max_size,
+        offline,
     })
 }
 
+fn get_cache_dir(value: Option<&str>) -> Option<PathBuf> {
+    let path = match value {
+        None => std::env::var("STORE_CACHE_DIR").ok()?,
+        Some(s) => s.to_string(),
+    };
+    Some(std::env::current_dir().unwrap().join(path))
+}
+
+fn get_cache_max_size(value: Option<&str>) -> Result<Option<u64>, String> {
+    match value {
+        None => Ok(None),
+        Some(s) => match store::parse_size(s) {
+            Some(size) => Ok(Some(size)),
+            None => Err(format!("Invalid cache size <{}>", s)),
+        },
+    }
+}
+
+fn get_jobs(value: Option<&str>) -> Result<usize, String> {
+    match value {
+        None => Ok(1),
+        Some(s) => match s.parse() {
+            Ok(jobs) if jobs > 0 => Ok(jobs),
+            _ => Err(format!("Invalid number of jobs <{}>", s)),
+        },
+    }
+}
+
+fn get_downloads(value: Option<&str>) -> Result<usize, String> {
+    match value {
+        None => Ok(4),
+        Some(s) => match s.parse() {
+            Ok(downloads) if downloads > 0 => Ok(downloads),
+            _ => Err(format!("Invalid number of downloads <{}>", s)),
+        },
+    }
+}
+
+fn get_build_timeout(value: Option<&str>) -> Result<Option<Duration>, String> {
+    match value {
+        None => Ok(None),
+        Some(s) => match store::parse_duration(s) {
+            Some(duration) => Ok(Some(duration)),
+            None => Err(format!("Invalid build timeout <{}>", s)),
+        },
+    }
+}
+
+fn get_retries(value: Option<&str>) -> Result<u32, String> {
+    match value {
+        None => Ok(3),
+        Some(s) => s
+            .parse()
+            .map_err(|_| format!("Invalid number of retries <{}>", s)),
+    }
+}
+
 fn get_db_file(value: Option<&str>) -> Result<PathBuf, String> {
     let path = match value {
         None => match std::env::var("STORE_DB_FILE") {
@@ -0,0 +1,55 @@
+use store::{eprint_line, print_line};
+
+// messages of a package install
+// prefixed with the package id when several packages are installed in parallel
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Output {
+    pub prefix: String,
+}
+
+impl Output {
+    pub fn new(package_id: &str, parallel: bool) -> Output {
+        let prefix = if parallel {
+            format!("[{}] ", package_id)
+        } else {
+            "".to_string()
+        };
+        Output { prefix }
+    }
+
+    pub fn println(&self, message: &str) {
+        print_line(&self.format(message));
+    }
+
+    pub fn eprintln(&self, message: &str) {
+        eprint_line(&self.format(message));
+    }
+
+    // each line is prefixed
+    fn format(&self, message: &str) -> String {
+        if self.prefix.is_empty() {
+            return message.to_string();
+        }
+        message
+            .split('\n')
+            .map(|line| format!("{}{}", self.prefix, line))
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_format() {
+        let output = Output::new("mypackage@0.1.0", false);
+        assert_eq!(output.format("Start\nEnd"), "Start\nEnd");
+        let output = Output::new("mypackage@0.1.0", true);
+        assert_eq!(
+            output.format("Start\nEnd"),
+            "[mypackage@0.1.0] Start\n[mypackage@0.1.0] End"
+        );
+    }
+}
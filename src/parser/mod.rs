@@ -1,4 +1,7 @@
+use crate::{Checksum, ExtractCommand, HashAlgorithm, VersionReq};
+use serde::{Deserialize, Serialize};
 use std::collections::HashSet;
+use std::time::Duration;
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct ParseError {
@@ -12,12 +15,19 @@ pub struct Parser {
     buffer: Vec<char>,
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct PackageDef {
     pub name: String,
     pub version: String,
     pub depends: Vec<String>,
     pub url: Option<String>,
+    pub mirrors: Vec<String>,
+    pub checksum: Option<Checksum>,
+    pub archive: Option<ExtractCommand>,
+    pub strip_components: Option<usize>,
+    pub subdir: Option<String>,
+    pub env: Vec<(String, String)>,
+    pub timeout: Option<u64>, // seconds, for the build script
     pub build: Option<String>,
 }
 
@@ -34,6 +44,13 @@ pub fn my_package_def() -> PackageDef {
         version: "0.1.0".to_string(),
         depends: vec![],
         url: Some("http://localhost:8000/mypackage-0.1.0-x86_64-linux.tar.gz".to_string()),
+        mirrors: vec![],
+        checksum: None,
+        archive: None,
+        strip_components: None,
+        subdir: None,
+        env: vec![],
+        timeout: None,
         build: None,
     }
 }
@@ -58,8 +75,13 @@ impl Parser {
         self.buffer.get(self.offset).copied()
     }
 
-    fn remaining(&self) -> String {
-        self.buffer.as_slice()[self.offset..].iter().collect()
+    // compared in place, without copying the rest of the buffer
+    fn starts_with(&self, s: &str) -> bool {
+        self.buffer[self.offset..]
+            .iter()
+            .copied()
+            .take(s.chars().count())
+            .eq(s.chars())
     }
 
     pub fn packages(&mut self) -> Result<Vec<PackageDef>, ParseError> {
@@ -101,7 +123,86 @@ impl Parser {
             self.skip_whitespace_or_comment();
             let url = self.url_field()?;
             self.skip_whitespace_or_comment();
+            let mirrors_offset = self.offset;
+            let mirrors = self.mirrors_field()?;
+            if !mirrors.is_empty() && url.is_none() {
+                let message = format!(
+                    "The package [{}@{}] defines mirrors without url field",
+                    name, version
+                );
+                return Err(ParseError {
+                    offset: mirrors_offset,
+                    message,
+                });
+            }
+            self.skip_whitespace_or_comment();
+            let checksum_offset = self.offset;
+            let checksum = self.checksum_field()?;
+            if checksum.is_some() && url.is_none() {
+                let message = format!(
+                    "The package [{}@{}] defines a checksum without url field",
+                    name, version
+                );
+                return Err(ParseError {
+                    offset: checksum_offset,
+                    message,
+                });
+            }
+            self.skip_whitespace_or_comment();
+            let archive_offset = self.offset;
+            let archive = self.archive_field()?;
+            if archive.is_some() && url.is_none() {
+                let message = format!(
+                    "The package [{}@{}] defines an archive without url field",
+                    name, version
+                );
+                return Err(ParseError {
+                    offset: archive_offset,
+                    message,
+                });
+            }
+            self.skip_whitespace_or_comment();
+            let strip_components_offset = self.offset;
+            let strip_components = self.strip_components_field()?;
+            if strip_components.is_some() && url.is_none() {
+                let message = format!(
+                    "The package [{}@{}] defines strip_components without url field",
+                    name, version
+                );
+                return Err(ParseError {
+                    offset: strip_components_offset,
+                    message,
+                });
+            }
+            self.skip_whitespace_or_comment();
+            let subdir_offset = self.offset;
+            let subdir = self.subdir_field()?;
+            if subdir.is_some() && url.is_none() {
+                let message = format!(
+                    "The package [{}@{}] defines a subdir without url field",
+                    name, version
+                );
+                return Err(ParseError {
+                    offset: subdir_offset,
+                    message,
+                });
+            }
+            self.skip_whitespace_or_comment();
+            let env = self.env_field()?;
+            self.skip_whitespace_or_comment();
+            let timeout_offset = self.offset;
+            let timeout = self.timeout_field()?;
+            self.skip_whitespace_or_comment();
             let build = self.build()?;
+            if timeout.is_some() && build.is_none() {
+                return Err(ParseError {
+                    message: format!(
+                        "The package [{}@{}] defines a timeout without build field",
+                        name, version
+                    ),
+                    offset: timeout_offset,
+                });
+            }
             if url.is_none() && build.is_none() {
                 let package_id = format!("{}@{}", name, version);
                 let message = format!(
@@ -115,13 +216,20 @@ impl Parser {
                 version,
                 depends,
                 url,
+                mirrors,
+                checksum,
+                archive,
+                strip_components,
+                subdir,
+                env,
+                timeout,
                 build,
             }))
         }
     }
 
     pub fn match_literal(&mut self, s: &str) -> Result<(), ParseError> {
-        if self.remaining().starts_with(s) {
+        if self.starts_with(s) {
             for _ in 0..s.len() {
                 self.read();
             }
@@ -269,6 +377,176 @@ impl Parser {
         }
     }
 
+    // additional urls tried in order when the download fails
+    // given by extra url fields and/or a mirrors field (space-separated)
+    pub fn mirrors_field(&mut self) -> Result<Vec<String>, ParseError> {
+        let mut mirrors = vec![];
+        while let Some(url) = self.url_field()? {
+            mirrors.push(url);
+            self.skip_whitespace_or_comment();
+        }
+        if self.match_literal("mirrors").is_ok() {
+            self.skip_space();
+            self.match_literal("=")?;
+            self.skip_space();
+            let value = self.url()?;
+            mirrors.extend(value.split_whitespace().map(|s| s.to_string()));
+        }
+        Ok(mirrors)
+    }
+
+    // sha256, sha512 or blake3 digest of the downloaded file
+    pub fn checksum_field(&mut self) -> Result<Option<Checksum>, ParseError> {
+        let algorithm = match HashAlgorithm::all()
+            .into_iter()
+            .find(|a| self.starts_with(a.name()))
+        {
+            None => return Ok(None),
+            Some(algorithm) => algorithm,
+        };
+        self.match_literal(algorithm.name())?;
+        self.skip_space();
+        self.match_literal("=")?;
+        self.skip_space();
+        let offset = self.offset;
+        let value = self.url()?;
+        if value.len() != algorithm.hex_len() || !value.chars().all(|c| c.is_ascii_hexdigit()) {
+            let message = format!(
+                "Invalid {} checksum, expecting {} hexadecimal characters",
+                algorithm.name(),
+                algorithm.hex_len()
+            );
+            return Err(ParseError { message, offset });
+        }
+        Ok(Some(Checksum { algorithm, value }))
+    }
+
+    // archive type, overriding the detection of the downloaded file
+    pub fn archive_field(&mut self) -> Result<Option<ExtractCommand>, ParseError> {
+        if self.match_literal("archive").is_err() {
+            return Ok(None);
+        }
+        self.skip_space();
+        self.match_literal("=")?;
+        self.skip_space();
+        let offset = self.offset;
+        let value = self.url()?;
+        match ExtractCommand::from_name(&value) {
+            Some(extract_command) => Ok(Some(extract_command)),
+            None => {
+                let names = ExtractCommand::all()
+                    .iter()
+                    .map(|c| c.name().to_string())
+                    .collect::<Vec<String>>();
+                let message = format!(
+                    "Invalid archive type <{}>, expecting one of {}",
+                    value,
+                    names.join(", ")
+                );
+                Err(ParseError { message, offset })
+            }
+        }
+    }
+
+    // number of leading directories to enter after the extraction
+    pub fn strip_components_field(&mut self) -> Result<Option<usize>, ParseError> {
+        if self.match_literal("strip_components").is_err() {
+            return Ok(None);
+        }
+        self.skip_space();
+        self.match_literal("=")?;
+        self.skip_space();
+        let offset = self.offset;
+        let value = self.url()?;
+        match value.parse() {
+            Ok(n) => Ok(Some(n)),
+            Err(_) => {
+                let message = format!("Invalid strip_components <{}>, expecting a number", value);
+                Err(ParseError { message, offset })
+            }
+        }
+    }
+
+    // directory (relative to the extracted/stripped directory) used as current directory
+    pub fn subdir_field(&mut self) -> Result<Option<String>, ParseError> {
+        if self.match_literal("subdir").is_err() {
+            return Ok(None);
+        }
+        self.skip_space();
+        self.match_literal("=")?;
+        self.skip_space();
+        let offset = self.offset;
+        let value = self.url()?;
+        let path = std::path::Path::new(&value);
+        let valid = path
+            .components()
+            .all(|c| matches!(c, std::path::Component::Normal(_)));
+        if valid {
+            Ok(Some(value))
+        } else {
+            let message = format!(
+                "Invalid subdir <{}>, expecting a relative path without ..",
+                value
+            );
+            Err(ParseError { message, offset })
+        }
+    }
+
+    // environment variables of the build script, one NAME=VALUE per line
+    // $NAME and ${NAME} are replaced by the variables defined before
+    pub fn env_field(&mut self) -> Result<Vec<(String, String)>, ParseError> {
+        let mut env = vec![];
+        if self.match_literal("env").is_err() {
+            return Ok(env);
+        }
+        self.skip_space();
+        self.match_literal("=")?;
+        self.skip_space();
+        env.push(self.env_variable()?);
+        while let Some(' ') = self.peek() {
+            self.skip_whitespace();
+            env.push(self.env_variable()?);
+        }
+        Ok(env)
+    }
+
+    fn env_variable(&mut self) -> Result<(String, String), ParseError> {
+        let offset = self.offset;
+        let value = self.url()?;
+        if let Some((name, value)) = value.split_once('=') {
+            let name = name.trim();
+            let valid = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+                && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+            if valid {
+                return Ok((name.to_string(), value.trim().to_string()));
+            }
+        }
+        let message = format!("Invalid env variable <{}>, expecting NAME=VALUE", value);
+        Err(ParseError { message, offset })
+    }
+
+    // maximum duration of the build script
+    pub fn timeout_field(&mut self) -> Result<Option<u64>, ParseError> {
+        if self.match_literal("timeout").is_err() {
+            return Ok(None);
+        }
+        self.skip_space();
+        self.match_literal("=")?;
+        self.skip_space();
+        let offset = self.offset;
+        let value = self.url()?;
+        match parse_duration(&value) {
+            Some(duration) => Ok(Some(duration.as_secs())),
+            None => {
+                let message = format!(
+                    "Invalid timeout <{}>, expecting a duration (90, 90s, 30m or 2h)",
+                    value
+                );
+                Err(ParseError { message, offset })
+            }
+        }
+    }
+
     pub fn build(&mut self) -> Result<Option<String>, ParseError> {
         if self.match_literal("build").is_err() {
             Ok(None)
@@ -292,7 +570,11 @@ impl Parser {
         }
     }
 
+    // comma-separated list of dependencies
+    // a token starting with an operator continues the constraints
+    // of the previous dependency (ruby >=2.7,<3)
     pub fn depends(&mut self) -> Result<Vec<String>, ParseError> {
+        let offset = self.offset;
         let mut value = "".to_string();
         //let start = self.offset;
         loop {
@@ -304,11 +586,25 @@ impl Parser {
                 }
             }
         }
-        Ok(value
-            .trim()
-            .split(',')
-            .map(|p| p.trim().to_string())
-            .collect())
+        let mut depends: Vec<String> = vec![];
+        for token in value.trim().split(',').map(|p| p.trim()) {
+            let is_constraint = token.starts_with(|c| "<>=^~".contains(c));
+            match depends.last_mut() {
+                Some(query) if is_constraint => {
+                    query.push(',');
+                    query.push_str(token);
+                }
+                _ => depends.push(token.to_string()),
+            }
+        }
+        for query in &depends {
+            if let Some((_, constraints)) = query.split_once(' ') {
+                if let Err(message) = VersionReq::parse(constraints.trim()) {
+                    return Err(ParseError { message, offset });
+                }
+            }
+        }
+        Ok(depends)
     }
 
     pub fn url(&mut self) -> Result<String, ParseError> {
@@ -355,6 +651,22 @@ impl Parser {
     }
 }
 
+// number of seconds, with an optional unit (s, m or h): 90, 90s, 30m, 2h
+pub fn parse_duration(s: &str) -> Option<Duration> {
+    let (value, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => s.split_at(index),
+        None => (s, "s"),
+    };
+    let value: u64 = value.parse().ok()?;
+    let secs = match unit {
+        "s" => value,
+        "m" => value.checked_mul(60)?,
+        "h" => value.checked_mul(3600)?,
+        _ => return None,
+    };
+    Some(Duration::from_secs(secs))
+}
+
 #[cfg(test)]
 mod tests {
     use super::super::my_package_def;
@@ -438,6 +750,200 @@ url = http://localhost:8000/mypackage-0.1.0-x86_64-linux.tar.gz
         assert_eq!(parser.offset, 18);
     }
 
+    #[test]
+    pub fn test_mirrors() {
+        let mut parser = Parser::init(
+            r#"[mypackage@0.1.0]
+url = http://localhost:8000/mypackage-0.1.0-x86_64-linux.tar.gz
+url = http://mirror1/mypackage-0.1.0-x86_64-linux.tar.gz
+mirrors = http://mirror2/mypackage-0.1.0-x86_64-linux.tar.gz http://mirror3/mypackage-0.1.0-x86_64-linux.tar.gz
+"#,
+        );
+        assert_eq!(
+            parser.package().unwrap().unwrap().mirrors,
+            vec![
+                "http://mirror1/mypackage-0.1.0-x86_64-linux.tar.gz".to_string(),
+                "http://mirror2/mypackage-0.1.0-x86_64-linux.tar.gz".to_string(),
+                "http://mirror3/mypackage-0.1.0-x86_64-linux.tar.gz".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    pub fn test_checksum() {
+        let mut parser = Parser::init(
+            r#"[mypackage@0.1.0]
+url = http://localhost:8000/mypackage-0.1.0-x86_64-linux.tar.gz
+sha256 = 77d5b8c04dee8cb2dd4ba80a11eb05003c3fcfb0ff905c3c2a87025dd9d8e1a8
+"#,
+        );
+        assert_eq!(
+            parser.package().unwrap().unwrap().checksum,
+            Some(Checksum {
+                algorithm: HashAlgorithm::Sha256,
+                value: "77d5b8c04dee8cb2dd4ba80a11eb05003c3fcfb0ff905c3c2a87025dd9d8e1a8"
+                    .to_string()
+            })
+        );
+
+        let mut parser = Parser::init("sha512 = 77d5b8c0\n");
+        assert_eq!(
+            parser.checksum_field().err().unwrap(),
+            ParseError {
+                offset: 9,
+                message: "Invalid sha512 checksum, expecting 128 hexadecimal characters"
+                    .to_string(),
+            }
+        );
+    }
+
+    #[test]
+    pub fn test_error_checksum_without_url() {
+        let mut parser = Parser::init(
+            r#"[mypackage@0.1.0]
+blake3 = 77d5b8c04dee8cb2dd4ba80a11eb05003c3fcfb0ff905c3c2a87025dd9d8e1a8
+build = true
+"#,
+        );
+        assert_eq!(
+            parser.package().err().unwrap(),
+            ParseError {
+                offset: 18,
+                message: "The package [mypackage@0.1.0] defines a checksum without url field"
+                    .to_string(),
+            }
+        );
+    }
+
+    #[test]
+    pub fn test_archive() {
+        let mut parser = Parser::init(
+            r#"[mypackage@0.1.0]
+url = http://localhost:8000/download?id=42
+archive = tar.xz
+"#,
+        );
+        assert_eq!(
+            parser.package().unwrap().unwrap().archive,
+            Some(ExtractCommand::TarXz)
+        );
+
+        let mut parser = Parser::init("archive = rar\n");
+        assert_eq!(
+            parser.archive_field().err().unwrap(),
+            ParseError {
+                offset: 10,
+                message:
+                    "Invalid archive type <rar>, expecting one of tar.gz, tar.xz, tar.bz2, tar.zst, tar, gz, xz, bz2, zst, zip, 7z, none"
+                        .to_string(),
+            }
+        );
+    }
+
+    #[test]
+    pub fn test_strip_components_subdir() {
+        let mut parser = Parser::init(
+            r#"[mypackage@0.1.0]
+url = http://localhost:8000/mypackage-0.1.0-x86_64-linux.tar.gz
+strip_components = 1
+subdir = dist/bin
+"#,
+        );
+        let package_def = parser.package().unwrap().unwrap();
+        assert_eq!(package_def.strip_components, Some(1));
+        assert_eq!(package_def.subdir, Some("dist/bin".to_string()));
+
+        let mut parser = Parser::init("strip_components = x\n");
+        assert_eq!(
+            parser.strip_components_field().err().unwrap(),
+            ParseError {
+                offset: 19,
+                message: "Invalid strip_components <x>, expecting a number".to_string(),
+            }
+        );
+
+        let mut parser = Parser::init("subdir = ../dist\n");
+        assert_eq!(
+            parser.subdir_field().err().unwrap(),
+            ParseError {
+                offset: 9,
+                message: "Invalid subdir <../dist>, expecting a relative path without .."
+                    .to_string(),
+            }
+        );
+    }
+
+    #[test]
+    pub fn test_env() {
+        let mut parser = Parser::init(
+            r#"[mypackage@0.1.0]
+depends = packageA
+env = CFLAGS=-O2
+      LDFLAGS=-L${DEP_PACKAGEA_DIR}/lib
+build = make
+"#,
+        );
+        let package_def = parser.package().unwrap().unwrap();
+        assert_eq!(
+            package_def.env,
+            vec![
+                ("CFLAGS".to_string(), "-O2".to_string()),
+                (
+                    "LDFLAGS".to_string(),
+                    "-L${DEP_PACKAGEA_DIR}/lib".to_string()
+                ),
+            ]
+        );
+        assert_eq!(package_def.build, Some("make\n".to_string()));
+
+        let mut parser = Parser::init("env = 1FLAGS=-O2\n");
+        assert_eq!(
+            parser.env_field().err().unwrap(),
+            ParseError {
+                offset: 6,
+                message: "Invalid env variable <1FLAGS=-O2>, expecting NAME=VALUE".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    pub fn test_timeout() {
+        let mut parser = Parser::init(
+            r#"[mypackage@0.1.0]
+timeout = 30m
+build = ./configure
+"#,
+        );
+        let package_def = parser.package().unwrap().unwrap();
+        assert_eq!(package_def.timeout, Some(1800));
+
+        let mut parser = Parser::init("timeout = 10d\n");
+        assert_eq!(
+            parser.timeout_field().err().unwrap(),
+            ParseError {
+                offset: 10,
+                message: "Invalid timeout <10d>, expecting a duration (90, 90s, 30m or 2h)"
+                    .to_string(),
+            }
+        );
+
+        let mut parser = Parser::init(
+            r#"[mypackage@0.1.0]
+url = http://localhost:8000/mypackage-0.1.0-x86_64-linux.tar.gz
+timeout = 30m
+"#,
+        );
+        assert_eq!(
+            parser.package().err().unwrap().message,
+            "The package [mypackage@0.1.0] defines a timeout without build field"
+        );
+
+        assert_eq!(parse_duration("90"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_duration("2h"), Some(Duration::from_secs(7200)));
+        assert_eq!(parse_duration("m"), None);
+        assert_eq!(parse_duration("1.5h"), None);
+    }
+
     #[test]
     pub fn test_depends() {
         let mut parser = Parser::init("packageA, packageB@1.0.0");
@@ -445,6 +951,21 @@ url = http://localhost:8000/mypackage-0.1.0-x86_64-linux.tar.gz
             parser.depends().unwrap(),
             vec!["packageA".to_string(), "packageB@1.0.0".to_string()]
         );
+
+        let mut parser = Parser::init("ruby >=2.7,<3, openjdk ^11");
+        assert_eq!(
+            parser.depends().unwrap(),
+            vec!["ruby >=2.7,<3".to_string(), "openjdk ^11".to_string()]
+        );
+
+        let mut parser = Parser::init("ruby >=");
+        assert_eq!(
+            parser.depends().err().unwrap(),
+            ParseError {
+                offset: 0,
+                message: "Invalid version constraint <>=>".to_string(),
+            }
+        );
     }
     //
     //     #[test]
@@ -0,0 +1,237 @@
+use crate::{Checksum, HashAlgorithm, PackageDef};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// directory of the store containing the receipts of the installed packages
+pub const RECEIPTS_DIR: &str = ".receipts";
+
+// Written for each installed package
+// in RECEIPTS_DIR/<package_id>.json
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Receipt {
+    pub id: String,
+    pub recipe: PackageDef,
+    pub source: Option<Source>,
+    pub installed_at: u64, // seconds since epoch
+    pub store_version: String,
+    pub dependencies: Vec<String>,
+    pub files: Vec<FileEntry>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Source {
+    pub url: String,
+    pub checksum: Checksum,
+}
+
+// path is relative to the package directory
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileEntry {
+    pub path: String,
+    #[serde(flatten)]
+    pub kind: FileKind,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum FileKind {
+    File { size: u64, sha256: String },
+    Symlink { target: String },
+}
+
+impl Receipt {
+    pub fn new(
+        recipe: &PackageDef,
+        source: Option<Source>,
+        dependencies: Vec<String>,
+        files: Vec<FileEntry>,
+    ) -> Receipt {
+        let installed_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        Receipt {
+            id: recipe.id(),
+            recipe: recipe.clone(),
+            source,
+            installed_at,
+            store_version: env!("CARGO_PKG_VERSION").to_string(),
+            dependencies,
+            files,
+        }
+    }
+
+    pub fn read(file: &Path) -> Result<Receipt, String> {
+        let content = fs::read_to_string(file)
+            .map_err(|e| format!("Can not read receipt {}: {}", file.display(), e))?;
+        serde_json::from_str(&content)
+            .map_err(|e| format!("Invalid receipt {}: {}", file.display(), e))
+    }
+
+    pub fn write(&self, file: &Path) -> Result<(), String> {
+        if let Some(dir) = file.parent() {
+            fs::create_dir_all(dir)
+                .map_err(|e| format!("Directory {} can not be created: {}", dir.display(), e))?;
+        }
+        // written to a temporary file and renamed, a receipt is never partial
+        let tmp_file = file.with_extension("json.tmp");
+        let content = serde_json::to_string_pretty(self).unwrap();
+        fs::write(&tmp_file, content)
+            .map_err(|e| format!("Can not write receipt {}: {}", tmp_file.display(), e))?;
+        fs::rename(&tmp_file, file)
+            .map_err(|e| format!("Can not write receipt {}: {}", file.display(), e))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FileChange {
+    Added(String),
+    Removed(String),
+    Modified(String),
+}
+
+impl Receipt {
+    // compare the files recorded at install time with the package directory
+    // sorted by path
+    pub fn changes(&self, package_dir: &Path) -> io::Result<Vec<FileChange>> {
+        let files = scan_files(package_dir)?;
+        // indexed by path, packages can have tens of thousands of files
+        let installed = self
+            .files
+            .iter()
+            .map(|f| (f.path.as_str(), f))
+            .collect::<HashMap<&str, &FileEntry>>();
+        let current = files
+            .iter()
+            .map(|f| (f.path.as_str(), f))
+            .collect::<HashMap<&str, &FileEntry>>();
+        let mut changes = vec![];
+        for file in &self.files {
+            match current.get(file.path.as_str()) {
+                None => changes.push(FileChange::Removed(file.path.clone())),
+                Some(f) if *f != file => changes.push(FileChange::Modified(file.path.clone())),
+                _ => {}
+            }
+        }
+        for file in &files {
+            if !installed.contains_key(file.path.as_str()) {
+                changes.push(FileChange::Added(file.path.clone()));
+            }
+        }
+        changes.sort_by(|a, b| a.path().cmp(b.path()));
+        Ok(changes)
+    }
+}
+
+impl FileChange {
+    pub fn path(&self) -> &str {
+        match self {
+            FileChange::Added(path) | FileChange::Removed(path) | FileChange::Modified(path) => {
+                path
+            }
+        }
+    }
+}
+
+// list recursively the files of a package directory, sorted by path
+// directories are not listed, symlinks are not followed
+pub fn scan_files(dir: &Path) -> io::Result<Vec<FileEntry>> {
+    let mut files = vec![];
+    scan_dir(dir, dir, &mut files)?;
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(files)
+}
+
+fn scan_dir(root: &Path, dir: &Path, files: &mut Vec<FileEntry>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let ty = entry.file_type()?;
+        let relative_path = path.strip_prefix(root).unwrap().display().to_string();
+        if ty.is_dir() {
+            scan_dir(root, &path, files)?;
+        } else if ty.is_symlink() {
+            let target = fs::read_link(&path)?.display().to_string();
+            files.push(FileEntry {
+                path: relative_path,
+                kind: FileKind::Symlink { target },
+            });
+        } else {
+            let size = entry.metadata()?.len();
+            let sha256 = Checksum::compute(HashAlgorithm::Sha256, &path)?.value;
+            files.push(FileEntry {
+                path: relative_path,
+                kind: FileKind::File { size, sha256 },
+            });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_scan_files() {
+        assert_eq!(
+            scan_files(Path::new("tests/directory_including_broken_links")).unwrap(),
+            vec![
+                FileEntry {
+                    path: "broken".to_string(),
+                    kind: FileKind::Symlink {
+                        target: "xxx".to_string()
+                    }
+                },
+                FileEntry {
+                    path: "find".to_string(),
+                    kind: FileKind::File {
+                        size: 17,
+                        sha256: "ec3d84cc76c173b91fe894439ea6d52ed68ee80d2d33cfe51c9674fc46b4fa06"
+                            .to_string()
+                    }
+                }
+            ]
+        );
+    }
+
+    #[test]
+    pub fn test_changes() {
+        let package_dir = Path::new("target/receipt/changes");
+        if package_dir.exists() {
+            fs::remove_dir_all(package_dir).unwrap();
+        }
+        fs::create_dir_all(package_dir.join("bin")).unwrap();
+        fs::write(package_dir.join("bin/hello"), "hello").unwrap();
+        fs::write(package_dir.join("README"), "readme").unwrap();
+        let files = scan_files(package_dir).unwrap();
+        let receipt = Receipt::new(&crate::my_package_def(), None, vec![], files);
+        assert_eq!(receipt.changes(package_dir).unwrap(), vec![]);
+
+        fs::write(package_dir.join("bin/hello"), "patched").unwrap();
+        fs::remove_file(package_dir.join("README")).unwrap();
+        fs::write(package_dir.join("bin/extra"), "").unwrap();
+        assert_eq!(
+            receipt.changes(package_dir).unwrap(),
+            vec![
+                FileChange::Removed("README".to_string()),
+                FileChange::Added("bin/extra".to_string()),
+                FileChange::Modified("bin/hello".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    pub fn test_read_write() {
+        let file = Path::new("target/receipt/mypackage@0.1.0.json");
+        let files = scan_files(Path::new("tests/linked_directory")).unwrap();
+        assert_eq!(files.len(), 3);
+        let receipt = Receipt::new(&crate::my_package_def(), None, vec![], files);
+        receipt.write(file).unwrap();
+        assert_eq!(Receipt::read(file).unwrap(), receipt);
+    }
+}
@@ -0,0 +1,244 @@
+use std::cmp::Ordering;
+use std::fmt;
+
+// Lenient version, compared component by component
+// missing components are equal to 0 (2.7 == 2.7.0)
+#[derive(Clone, Debug)]
+pub struct Version {
+    parts: Vec<Part>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Part {
+    Number(u64),
+    Text(String),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VersionReq {
+    pub comparators: Vec<Comparator>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Comparator {
+    pub op: Op,
+    pub version: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Op {
+    Exact,
+    Greater,
+    GreaterEq,
+    Less,
+    LessEq,
+    Caret,
+    Tilde,
+}
+
+impl Version {
+    pub fn parse(s: &str) -> Version {
+        let parts = s
+            .split(['.', '-'])
+            .map(|p| match p.parse::<u64>() {
+                Ok(n) => Part::Number(n),
+                Err(_) => Part::Text(p.to_string()),
+            })
+            .collect();
+        Version { parts }
+    }
+
+    fn part(&self, index: usize) -> Part {
+        self.parts.get(index).cloned().unwrap_or(Part::Number(0))
+    }
+
+    // the upper bound of a caret/tilde range,
+    // obtained by incrementing the component at index
+    fn bump(&self, index: usize) -> Version {
+        let mut parts = vec![];
+        for i in 0..index {
+            parts.push(self.part(i));
+        }
+        let n = match self.part(index) {
+            Part::Number(n) => n + 1,
+            Part::Text(_) => 1,
+        };
+        parts.push(Part::Number(n));
+        Version { parts }
+    }
+}
+
+impl Ord for Part {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (Part::Number(a), Part::Number(b)) => a.cmp(b),
+            (Part::Text(a), Part::Text(b)) => a.cmp(b),
+            (Part::Number(_), Part::Text(_)) => Ordering::Greater,
+            (Part::Text(_), Part::Number(_)) => Ordering::Less,
+        }
+    }
+}
+
+impl PartialOrd for Part {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Version {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let len = self.parts.len().max(other.parts.len());
+        for i in 0..len {
+            let ordering = self.part(i).cmp(&other.part(i));
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
+        }
+        Ordering::Equal
+    }
+}
+
+impl PartialOrd for Version {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Version {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Version {}
+
+impl VersionReq {
+    // comma-separated list of comparators
+    // all of them must be satisfied
+    pub fn parse(s: &str) -> Result<VersionReq, String> {
+        let mut comparators = vec![];
+        for token in s.split(',') {
+            comparators.push(Comparator::parse(token.trim())?);
+        }
+        Ok(VersionReq { comparators })
+    }
+
+    // return the first comparator not satisfied by the version
+    pub fn unsatisfied(&self, version: &str) -> Option<&Comparator> {
+        self.comparators.iter().find(|c| !c.matches(version))
+    }
+
+    pub fn matches(&self, version: &str) -> bool {
+        self.unsatisfied(version).is_none()
+    }
+}
+
+impl Comparator {
+    pub fn parse(s: &str) -> Result<Comparator, String> {
+        let operators = [
+            (">=", Op::GreaterEq),
+            ("<=", Op::LessEq),
+            (">", Op::Greater),
+            ("<", Op::Less),
+            ("=", Op::Exact),
+            ("^", Op::Caret),
+            ("~", Op::Tilde),
+        ];
+        let (op, version) = match operators.iter().find(|(prefix, _)| s.starts_with(prefix)) {
+            Some((prefix, op)) => (*op, s[prefix.len()..].trim()),
+            None => (Op::Exact, s),
+        };
+        let valid = !version.is_empty()
+            && version
+                .chars()
+                .all(|c| c.is_alphanumeric() || c == '.' || c == '-' || c == '_');
+        if valid {
+            Ok(Comparator {
+                op,
+                version: version.to_string(),
+            })
+        } else {
+            Err(format!("Invalid version constraint <{}>", s))
+        }
+    }
+
+    pub fn matches(&self, version: &str) -> bool {
+        let version = Version::parse(version);
+        let bound = Version::parse(&self.version);
+        match self.op {
+            Op::Exact => version == bound,
+            Op::Greater => version > bound,
+            Op::GreaterEq => version >= bound,
+            Op::Less => version < bound,
+            Op::LessEq => version <= bound,
+            Op::Caret => {
+                // the first non-zero component must not change
+                let index = bound
+                    .parts
+                    .iter()
+                    .position(|p| *p != Part::Number(0))
+                    .unwrap_or(bound.parts.len() - 1);
+                version >= bound && version < bound.bump(index)
+            }
+            Op::Tilde => {
+                // ~2 allows 2.x, ~2.7 allows 2.7.x
+                let index = if bound.parts.len() > 1 { 1 } else { 0 };
+                version >= bound && version < bound.bump(index)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Comparator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let op = match self.op {
+            Op::Exact => "=",
+            Op::Greater => ">",
+            Op::GreaterEq => ">=",
+            Op::Less => "<",
+            Op::LessEq => "<=",
+            Op::Caret => "^",
+            Op::Tilde => "~",
+        };
+        write!(f, "{}{}", op, self.version)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_version_order() {
+        assert_eq!(Version::parse("2.7"), Version::parse("2.7.0"));
+        assert!(Version::parse("2.10.0") > Version::parse("2.9.1"));
+        assert!(Version::parse("11.0.2") > Version::parse("11"));
+        assert!(Version::parse("1.0.0") > Version::parse("1.0.0-rc1"));
+    }
+
+    #[test]
+    pub fn test_version_req() {
+        let req = VersionReq::parse(">=2.7,<3").unwrap();
+        assert!(req.matches("2.7.0"));
+        assert!(req.matches("2.7.5"));
+        assert!(!req.matches("3.0.1"));
+        assert_eq!(req.unsatisfied("2.6.0").unwrap().to_string(), ">=2.7");
+
+        let req = VersionReq::parse("^11").unwrap();
+        assert!(req.matches("11.0.2"));
+        assert!(!req.matches("17.0.1"));
+
+        let req = VersionReq::parse("^0.2.1").unwrap();
+        assert!(req.matches("0.2.5"));
+        assert!(!req.matches("0.3.0"));
+
+        let req = VersionReq::parse("~2.7").unwrap();
+        assert!(req.matches("2.7.9"));
+        assert!(!req.matches("2.8.0"));
+
+        assert_eq!(
+            VersionReq::parse(">=").err().unwrap(),
+            "Invalid version constraint <>=>"
+        );
+    }
+}